@@ -1,5 +1,5 @@
 use cumulus_primitives_core::ParaId;
-use xcmp_bank_node::{AccountId, AuraId, Signature, EXISTENTIAL_DEPOSIT};
+use xcmp_bank_node::{AccountId, AuraId, Signature, EXISTENTIAL_DEPOSIT, SAFE_XCM_VERSION};
 use sc_chain_spec::{ChainSpecExtension, ChainSpecGroup};
 use sc_service::ChainType;
 use serde::{Deserialize, Serialize};
@@ -208,5 +208,8 @@ fn testnet_genesis(
 		aura: Default::default(),
 		aura_ext: Default::default(),
 		parachain_system: Default::default(),
+		polkadot_xcm: xcmp_bank_node::PolkadotXcmConfig {
+			safe_xcm_version: Some(SAFE_XCM_VERSION),
+		},
 	}
 }
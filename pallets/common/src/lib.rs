@@ -1,13 +1,70 @@
 #![cfg_attr(not(feature = "std"), no_std)]
-use codec::{Decode, Encode};
+use codec::{Decode, DecodeLimit, Encode};
 use scale_info::TypeInfo;
-
-use frame_support::{
-    sp_runtime::RuntimeDebug,
+use sp_std::{
+    convert::{TryFrom, TryInto},
+    prelude::*,
 };
 
+use frame_support::sp_runtime::{Perbill, Percent, RuntimeDebug};
+use frame_support::weights::Weight;
+use frame_support::BoundedVec;
+use sp_core::H256;
+
+use cumulus_primitives_core::ParaId;
+use xcm::latest::{Junction, Junctions, MultiLocation};
+
+pub mod migrations;
+pub mod transport;
+
+#[cfg(test)]
+mod tests;
+
+/// Globally unique order identifier
+pub type OrderId = H256;
+
+/// Derives the identifier of an order from its client, device, client side nonce and the
+/// chain the order originates from.
+pub fn order_id<AccountId: Encode, ChainId: Encode>(
+    client: &AccountId,
+    device: &AccountId,
+    nonce: u64,
+    chain: &ChainId,
+) -> OrderId {
+    (client, device, nonce, chain).using_encoded(sp_io::hashing::blake2_256).into()
+}
+
+/// Location of a sibling parachain.
+pub fn sibling_location(para_id: ParaId) -> MultiLocation {
+    MultiLocation::new(1, Junctions::X1(Junction::Parachain(para_id.into())))
+}
+
+/// The account junction a location ends with, if it points to an account.
+pub fn account_junction(location: &MultiLocation) -> Option<Junction> {
+    match location.last() {
+        Some(junction @ Junction::AccountId32 { .. })
+        | Some(junction @ Junction::AccountKey20 { .. }) => Some(junction.clone()),
+        _ => None,
+    }
+}
+
+/// The consensus system holding a location, the location itself unless it points to an
+/// account. Protocol messages for a device are sent there.
+pub fn chain_location(location: &MultiLocation) -> MultiLocation {
+    let mut chain = location.clone();
+    if account_junction(location).is_some() {
+        chain.take_last();
+    }
+    chain
+}
+
+/// Whether a location is outside of this chain, i.e. a possible protocol partner.
+pub fn is_foreign(location: &MultiLocation) -> bool {
+    location.parents > 0
+}
+
 #[cfg_attr(feature = "std", derive(Debug))]
-#[derive(Encode, Decode, PartialEq, TypeInfo, Clone)]
+#[derive(Encode, Decode, PartialEq, Eq, TypeInfo, Clone, Copy)]
 pub enum DeviceState {
     /// Device is off
     Off,
@@ -26,7 +83,149 @@ impl Default for DeviceState {
     }
 }
 
-#[derive(Encode, Decode, Default, Clone, RuntimeDebug, PartialEq, TypeInfo)]
+/// Reason of a device state change
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq, TypeInfo)]
+pub enum DeviceEvent {
+    /// The owner switches the device on
+    SwitchOn,
+    /// The owner switches the device off
+    SwitchOff,
+    /// An order for the device is placed
+    Order,
+    /// The device accepts its order
+    Accept,
+    /// The order is rejected, the device stays on
+    Reject,
+    /// The order is rejected, the device goes off
+    RejectOff,
+    /// The order is done, the device stays on
+    Done,
+    /// The order is done, the device goes off
+    DoneOff,
+    /// The client cancels the order
+    Cancel,
+    /// The account of the device is reaped
+    Reaped,
+}
+
+impl DeviceEvent {
+    pub fn reject(onoff: bool) -> Self {
+        if onoff {
+            DeviceEvent::Reject
+        } else {
+            DeviceEvent::RejectOff
+        }
+    }
+
+    pub fn done(onoff: bool) -> Self {
+        if onoff {
+            DeviceEvent::Done
+        } else {
+            DeviceEvent::DoneOff
+        }
+    }
+}
+
+/// Why a device can't take a transition
+#[derive(Clone, Copy, RuntimeDebug, PartialEq, Eq)]
+pub enum TransitionError {
+    /// The event isn't allowed in the current state of the device
+    NotAllowed { from: DeviceState, event: DeviceEvent },
+    /// The device is abandoned, only settling its order is allowed
+    Abandoned { event: DeviceEvent },
+}
+
+/// The device life cycle shared by the client and the service chains.
+pub struct DeviceStateMachine;
+
+impl DeviceStateMachine {
+    /// Event, states it is allowed in and the state it leads to. The first matching row wins.
+    pub const TRANSITIONS: &'static [(DeviceEvent, &'static [DeviceState], DeviceState)] = {
+        use DeviceEvent as E;
+        use DeviceState::*;
+        &[
+            // an abandoned device leaves Timewait only once its order is settled
+            (E::SwitchOn, &[Off, Ready], Ready),
+            (E::SwitchOff, &[Off, Ready], Off),
+            (E::Order, &[Ready], Busy),
+            (E::Accept, &[Busy], Accepted),
+            (E::Reject, &[Busy, Accepted], Ready),
+            (E::RejectOff, &[Busy, Accepted], Off),
+            // the client chain may learn about the completion before the acceptance
+            (E::Done, &[Busy, Accepted], Ready),
+            (E::DoneOff, &[Busy, Accepted], Off),
+            (E::Cancel, &[Busy, Accepted], Ready),
+            (E::Reaped, &[Ready], Off),
+            (E::Reaped, &[Busy, Accepted], Timewait),
+            // an abandoned device goes off as soon as its order is settled
+            (E::Reject, &[Timewait], Off),
+            (E::RejectOff, &[Timewait], Off),
+            (E::Done, &[Timewait], Off),
+            (E::DoneOff, &[Timewait], Off),
+            (E::Cancel, &[Timewait], Off),
+        ]
+    };
+
+    /// Returns the state `event` moves a device in state `from` to.
+    pub fn next(from: DeviceState, event: DeviceEvent) -> Result<DeviceState, TransitionError> {
+        Self::TRANSITIONS
+            .iter()
+            .find(|(e, states, _)| *e == event && states.contains(&from))
+            .map(|(_, _, to)| *to)
+            .ok_or(if from == DeviceState::Timewait {
+                TransitionError::Abandoned { event }
+            } else {
+                TransitionError::NotAllowed { from, event }
+            })
+    }
+}
+
+/// Identifier of a payload schema registered on a service chain
+pub type SchemaId = u32;
+
+/// Order payload tagged with the schema its bytes follow
+pub type SchemaPayload<MaxLen> = (SchemaId, BoundedVec<u8, MaxLen>);
+
+/// Order payloads which tell the schema they follow.
+pub trait PayloadSchema {
+    fn schema_id(&self) -> SchemaId;
+}
+
+impl<MaxLen> PayloadSchema for SchemaPayload<MaxLen> {
+    fn schema_id(&self) -> SchemaId {
+        self.0
+    }
+}
+
+/// Where the client of an order lives, seen from the chain holding the order.
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq, TypeInfo)]
+pub enum OrderOrigin<Location> {
+    /// The client is an account of this chain
+    Local,
+    /// The client is an account of another chain
+    Remote(Location),
+}
+impl<Location> Default for OrderOrigin<Location> {
+    fn default() -> Self {
+        OrderOrigin::Local
+    }
+}
+
+impl<Location> OrderOrigin<Location> {
+    /// The chain responses have to be sent to, if any.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            OrderOrigin::Local => None,
+            OrderOrigin::Remote(location) => Some(location),
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self, OrderOrigin::Local)
+    }
+}
+
+#[derive(Encode, Decode, Default, Clone, RuntimeDebug, PartialEq, Eq, TypeInfo)]
 pub struct OrderBase<Payload: Encode + Decode, Balance, Moment, AccountId> {
     pub until: Moment,
     pub data: Payload,
@@ -37,31 +236,35 @@ pub struct OrderBase<Payload: Encode + Decode, Balance, Moment, AccountId> {
 impl<Payload: Encode + Decode, Balance, Moment, AccountId>
     OrderBase<Payload, Balance, Moment, AccountId>
 {
-    pub fn convert<ParaId: From<u32>>(
+    pub fn convert<Location>(
         self,
+        id: OrderId,
         client: AccountId,
-    ) -> Order<Payload, Balance, Moment, AccountId, ParaId> {
+        origin: OrderOrigin<Location>,
+    ) -> Order<Payload, Balance, Moment, AccountId, Location> {
         Order {
+            id,
             until: self.until,
             data: self.data,
             fee: self.fee,
             client,
-            paraid: 0.into(),
+            origin,
         }
     }
 }
 
 #[derive(Encode, Decode, Default, Clone, RuntimeDebug, PartialEq, TypeInfo)]
-pub struct Order<Payload: Encode + Decode, Balance, Moment, AccountId, ParaId> {
+pub struct Order<Payload: Encode + Decode, Balance, Moment, AccountId, Location> {
+    pub id: OrderId,
     pub until: Moment,
     pub data: Payload,
     pub fee: Balance,
     pub client: AccountId,
-    pub paraid: ParaId,
+    pub origin: OrderOrigin<Location>,
 }
 
-impl<Payload: Encode + Decode, Balance, Moment, AccountId, ParaId>
-    Order<Payload, Balance, Moment, AccountId, ParaId>
+impl<Payload: Encode + Decode, Balance, Moment, AccountId, Location>
+    Order<Payload, Balance, Moment, AccountId, Location>
 {
     pub fn convert(self, device: AccountId) -> OrderBase<Payload, Balance, Moment, AccountId> {
         OrderBase {
@@ -73,10 +276,285 @@ impl<Payload: Encode + Decode, Balance, Moment, AccountId, ParaId>
     }
 }
 
-#[derive(codec::Encode, codec::Decode)]
-pub enum XCMPMessage<XAccountId, XBalance, Payout: Encode + Decode, Moment> {
-    NewOrder(XAccountId, OrderBase<Payout, XBalance, Moment, XAccountId>),
-    OrderAccept(XAccountId, XAccountId),
-    OrderReject(XAccountId, XAccountId, bool),
-    OrderDone(XAccountId, XAccountId, bool),
+/// Highest version of the order protocol understood by this code.
+pub const XCMP_VERSION: u32 = 2;
+/// Lowest version of the order protocol still produced and accepted.
+pub const MIN_XCMP_VERSION: u32 = 1;
+/// Nesting limit of inbound protocol messages, batches make the message type recursive.
+pub const MAX_XCMP_DECODE_DEPTH: u32 = 32;
+
+/// Position of `receive_order` among the calls of `pallet_xchange_service`.
+pub const RECEIVE_ORDER_CALL_INDEX: u8 = 11;
+/// Position of `receive_response` among the calls of `pallet_xchange`.
+pub const RECEIVE_RESPONSE_CALL_INDEX: u8 = 8;
+/// Weight the calls receiving protocol messages declare for each message they carry. The
+/// heaviest message, `OrderDone` applied by a client chain, takes 9 reads and 11 writes, about
+/// 1.3e9 on RocksDB; the rest is headroom. The pallets check every message fits in it.
+pub const RECEIVE_MESSAGE_WEIGHT: Weight = 2_000_000_000;
+/// Message fees a sovereign account on a partner chain spends before it is refilled.
+pub const TOP_UP_MESSAGES: u32 = 16;
+
+/// Frozen version 1 of the order protocol.
+pub mod v1 {
+    use super::*;
+
+    #[derive(Encode, Decode, Default, Clone, RuntimeDebug, PartialEq, Eq, TypeInfo)]
+    pub struct OrderBase<Payload: Encode + Decode, Balance, Moment, AccountId> {
+        pub until: Moment,
+        pub data: Payload,
+        pub fee: Balance,
+        pub device: AccountId,
+    }
+
+    #[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq, TypeInfo)]
+    pub enum XCMPMessage<XAccountId, XBalance, Payout: Encode + Decode, Moment> {
+        NewOrder(XAccountId, OrderBase<Payout, XBalance, Moment, XAccountId>),
+        OrderAccept(XAccountId, XAccountId),
+        OrderReject(XAccountId, XAccountId, bool),
+        OrderDone(XAccountId, XAccountId, bool),
+    }
+}
+
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq, TypeInfo)]
+pub enum XCMPMessage<XAccountId, XBalance, Payout: Encode + Decode, Moment, Res> {
+    NewOrder(OrderId, XAccountId, OrderBase<Payout, XBalance, Moment, XAccountId>),
+    OrderAccept(OrderId, XAccountId, XAccountId),
+    OrderReject(OrderId, XAccountId, XAccountId, bool),
+    /// Completion of an order, with the optional result of the work.
+    OrderDone(OrderId, XAccountId, XAccountId, bool, Option<Res>),
+    /// The device reports how far it has got with an accepted order, with an optional status.
+    OrderProgress(OrderId, XAccountId, XAccountId, Percent, Option<Vec<u8>>),
+    /// The sender announces the highest protocol version it understands.
+    Version(u32),
+    /// Messages to the same chain collected during a block, applied one at a time.
+    Batch(Vec<XCMPMessage<XAccountId, XBalance, Payout, Moment, Res>>),
+    /// Liveness probe, answered with a `Pong` carrying the same nonce.
+    Ping(u64),
+    Pong(u64),
+    /// The client chain has settled an order left past its deadline, the device is free again.
+    OrderExpired(OrderId, XAccountId, XAccountId),
+    /// A device registers on the service chain: device, penalty, wcd, whether it's on and the
+    /// share of the fee it keeps when an accepted order is cancelled.
+    DeviceRegistered(XAccountId, XBalance, Moment, bool, Perbill),
+    /// A registered device changes its profile or switches on or off.
+    DeviceUpdated(XAccountId, XBalance, Moment, bool, Perbill),
+    /// A device leaves the service chain.
+    DeviceRemoved(XAccountId),
+    /// The client cancels an order before its deadline.
+    CancelOrder(OrderId, XAccountId, XAccountId),
+    /// The service chain has dropped a cancelled order, the device is owed the given share of
+    /// the fee.
+    OrderCancelled(OrderId, XAccountId, XAccountId, XBalance),
+    /// The payload schemas a registered device takes orders with.
+    DeviceSchemas(XAccountId, Vec<SchemaId>),
+}
+
+impl<XAccountId, XBalance, Payout: Encode + Decode, Moment>
+    v1::XCMPMessage<XAccountId, XBalance, Payout, Moment>
+{
+    /// Upgrades the message to the latest layout. Version 1 messages carry no order id, so
+    /// `order_id` resolves it from the client and the device of the message.
+    pub fn upgrade<Res>(
+        self,
+        order_id: impl FnOnce(&XAccountId, &XAccountId) -> OrderId,
+    ) -> XCMPMessage<XAccountId, XBalance, Payout, Moment, Res> {
+        match self {
+            v1::XCMPMessage::NewOrder(client, order) => {
+                let v1::OrderBase { until, data, fee, device } = order;
+                let id = order_id(&client, &device);
+                XCMPMessage::NewOrder(id, client, OrderBase { until, data, fee, device })
+            },
+            v1::XCMPMessage::OrderAccept(client, device) => {
+                XCMPMessage::OrderAccept(order_id(&client, &device), client, device)
+            },
+            v1::XCMPMessage::OrderReject(client, device, onoff) => {
+                XCMPMessage::OrderReject(order_id(&client, &device), client, device, onoff)
+            },
+            v1::XCMPMessage::OrderDone(client, device, onoff) => {
+                XCMPMessage::OrderDone(order_id(&client, &device), client, device, onoff, None)
+            },
+        }
+    }
+}
+
+impl<XAccountId, XBalance, Payout: Encode + Decode, Moment, Res>
+    TryFrom<XCMPMessage<XAccountId, XBalance, Payout, Moment, Res>>
+    for v1::XCMPMessage<XAccountId, XBalance, Payout, Moment>
+{
+    type Error = ();
+
+    fn try_from(msg: XCMPMessage<XAccountId, XBalance, Payout, Moment, Res>) -> Result<Self, ()> {
+        Ok(match msg {
+            XCMPMessage::NewOrder(_, client, order) => {
+                let OrderBase { until, data, fee, device } = order;
+                v1::XCMPMessage::NewOrder(client, v1::OrderBase { until, data, fee, device })
+            },
+            XCMPMessage::OrderAccept(_, client, device) => {
+                v1::XCMPMessage::OrderAccept(client, device)
+            },
+            XCMPMessage::OrderReject(_, client, device, onoff) => {
+                v1::XCMPMessage::OrderReject(client, device, onoff)
+            },
+            // the result is lost for version 1 partners, but not the completion
+            XCMPMessage::OrderDone(_, client, device, onoff, _) => {
+                v1::XCMPMessage::OrderDone(client, device, onoff)
+            },
+            XCMPMessage::OrderProgress(..)
+            | XCMPMessage::Version(_)
+            | XCMPMessage::Batch(_)
+            | XCMPMessage::Ping(_)
+            | XCMPMessage::Pong(_)
+            | XCMPMessage::OrderExpired(..)
+            | XCMPMessage::DeviceRegistered(..)
+            | XCMPMessage::DeviceUpdated(..)
+            | XCMPMessage::DeviceRemoved(_)
+            | XCMPMessage::CancelOrder(..)
+            | XCMPMessage::OrderCancelled(..)
+            | XCMPMessage::DeviceSchemas(..) => return Err(()),
+        })
+    }
+}
+
+/// Envelope of every protocol message exchanged between the client and the service chains.
+///
+/// The codec index of a variant equals its protocol version, so the version of an encoded
+/// message is its first byte.
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq, TypeInfo)]
+pub enum VersionedXCMPMessage<XAccountId, XBalance, Payout: Encode + Decode, Moment, Res> {
+    #[codec(index = 1)]
+    V1(v1::XCMPMessage<XAccountId, XBalance, Payout, Moment>),
+    #[codec(index = 2)]
+    V2(XCMPMessage<XAccountId, XBalance, Payout, Moment, Res>),
+}
+
+impl<XAccountId, XBalance, Payout: Encode + Decode, Moment, Res>
+    VersionedXCMPMessage<XAccountId, XBalance, Payout, Moment, Res>
+{
+    pub fn version(&self) -> u32 {
+        match self {
+            VersionedXCMPMessage::V1(_) => 1,
+            VersionedXCMPMessage::V2(_) => 2,
+        }
+    }
+
+    /// Reads the protocol version of an encoded message without decoding it.
+    pub fn peek_version(data: &[u8]) -> Option<u32> {
+        data.first().map(|v| *v as u32)
+    }
+
+    /// Decodes a message a partner sent, refusing batches nested deeper than
+    /// `MAX_XCMP_DECODE_DEPTH`.
+    pub fn decode_limited(data: &[u8]) -> Result<Self, codec::Error> {
+        Self::decode_with_depth_limit(MAX_XCMP_DECODE_DEPTH, data)
+    }
+
+    /// Declared weight of the call receiving the encoded message `data`. Data which doesn't
+    /// decode is only reported, that fits in the weight of a single message.
+    pub fn receive_weight_of(data: &[u8]) -> Weight {
+        Self::decode_limited(data).map_or(RECEIVE_MESSAGE_WEIGHT, |msg| msg.receive_weight())
+    }
+
+    /// Converts the message into the given protocol version, down-converting when the
+    /// partner is older. Fails if the version is unknown or the message can't be expressed in it.
+    pub fn into_version(self, version: u32) -> Result<Self, ()> {
+        match version {
+            1 => Ok(VersionedXCMPMessage::V1(match self {
+                VersionedXCMPMessage::V1(msg) => msg,
+                VersionedXCMPMessage::V2(msg) => msg.try_into()?,
+            })),
+            2 => match self {
+                VersionedXCMPMessage::V2(msg) => Ok(VersionedXCMPMessage::V2(msg)),
+                // order ids can't be invented without the receiver's storage
+                VersionedXCMPMessage::V1(_) => Err(()),
+            },
+            _ => Err(()),
+        }
+    }
+
+    /// Converts the message into the latest layout, see [`v1::XCMPMessage::upgrade`].
+    pub fn into_latest(
+        self,
+        order_id: impl FnOnce(&XAccountId, &XAccountId) -> OrderId,
+    ) -> XCMPMessage<XAccountId, XBalance, Payout, Moment, Res> {
+        match self {
+            VersionedXCMPMessage::V1(msg) => msg.upgrade(order_id),
+            VersionedXCMPMessage::V2(msg) => msg,
+        }
+    }
+
+    /// Declared weight of the call receiving the message, a `Transact` of it has to allow for it.
+    pub fn receive_weight(&self) -> Weight {
+        let count = match self {
+            VersionedXCMPMessage::V1(_) => 1,
+            VersionedXCMPMessage::V2(msg) => msg.message_count(),
+        };
+        RECEIVE_MESSAGE_WEIGHT.saturating_mul(count.max(1))
+    }
+}
+
+impl<XAccountId, XBalance, Payout: Encode + Decode, Moment, Res>
+    XCMPMessage<XAccountId, XBalance, Payout, Moment, Res>
+{
+    /// Number of messages carried, counting the ones of a batch.
+    pub fn message_count(&self) -> u64 {
+        match self {
+            XCMPMessage::Batch(msgs) => msgs.iter().map(Self::message_count).sum(),
+            _ => 1,
+        }
+    }
 }
+
+impl<XAccountId, XBalance, Payout, Moment, Res>
+    XCMPMessage<XAccountId, XBalance, Payout, Moment, Res>
+where
+    XAccountId: Default,
+    XBalance: Default,
+    Payout: Encode + Decode + Default,
+    Moment: Default,
+{
+    /// One message of every kind but `Batch`, which weighs as much as the messages it carries.
+    /// A new kind of message belongs here too.
+    pub fn samples() -> Vec<Self> {
+        let id = OrderId::default();
+        let account = XAccountId::default;
+        vec![
+            XCMPMessage::NewOrder(id, account(), OrderBase::default()),
+            XCMPMessage::OrderAccept(id, account(), account()),
+            XCMPMessage::OrderReject(id, account(), account(), false),
+            XCMPMessage::OrderDone(id, account(), account(), false, None),
+            XCMPMessage::OrderProgress(id, account(), account(), Percent::default(), None),
+            XCMPMessage::Version(XCMP_VERSION),
+            XCMPMessage::Ping(0),
+            XCMPMessage::Pong(0),
+            XCMPMessage::OrderExpired(id, account(), account()),
+            XCMPMessage::DeviceRegistered(
+                account(),
+                XBalance::default(),
+                Moment::default(),
+                false,
+                Perbill::default(),
+            ),
+            XCMPMessage::DeviceUpdated(
+                account(),
+                XBalance::default(),
+                Moment::default(),
+                false,
+                Perbill::default(),
+            ),
+            XCMPMessage::DeviceRemoved(account()),
+            XCMPMessage::CancelOrder(id, account(), account()),
+            XCMPMessage::OrderCancelled(id, account(), account(), XBalance::default()),
+            XCMPMessage::DeviceSchemas(account(), Vec::new()),
+        ]
+    }
+}
+
+impl<XAccountId, XBalance, Payout: Encode + Decode, Moment, Res>
+    From<XCMPMessage<XAccountId, XBalance, Payout, Moment, Res>>
+    for VersionedXCMPMessage<XAccountId, XBalance, Payout, Moment, Res>
+{
+    fn from(msg: XCMPMessage<XAccountId, XBalance, Payout, Moment, Res>) -> Self {
+        VersionedXCMPMessage::V2(msg)
+    }
+}
+
@@ -0,0 +1,29 @@
+//! Helpers of the storage migrations of the xchange pallets.
+
+use codec::{Decode, Encode, FullCodec};
+use frame_support::storage::{unhashed, IterableStorageMap, StorageMap};
+use sp_std::vec::Vec;
+
+/// Rewrites every value of `Map` from the layout `Old` into the layout `New`, which needn't be
+/// the value type of `Map`: a migration step may write a layout only the next step knows.
+/// Values `f` returns `None` for, or which don't decode as `Old`, are removed. Returns how many
+/// values were visited.
+pub fn translate_layout<Map, K, V, Old, New, F>(mut f: F) -> u64
+where
+    Map: IterableStorageMap<K, V> + StorageMap<K, V>,
+    K: FullCodec,
+    V: FullCodec,
+    Old: Decode,
+    New: Encode,
+    F: FnMut(&K, Old) -> Option<New>,
+{
+    let keys: Vec<K> = Map::iter_keys().collect();
+    for key in &keys {
+        let hashed = Map::hashed_key_for(key);
+        match unhashed::get::<Old>(&hashed).and_then(|old| f(key, old)) {
+            Some(new) => unhashed::put(&hashed, &new),
+            None => unhashed::kill(&hashed),
+        }
+    }
+    keys.len() as u64
+}
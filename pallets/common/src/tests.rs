@@ -0,0 +1,190 @@
+use crate::{
+    order_id, v1, DeviceEvent as E, DeviceState::*, DeviceStateMachine, OrderBase, TransitionError,
+    VersionedXCMPMessage, XCMPMessage, MAX_XCMP_DECODE_DEPTH, XCMP_VERSION,
+};
+use codec::Encode;
+use sp_core::H256;
+
+type Message = XCMPMessage<u64, u128, u32, u64, u32>;
+type Versioned = VersionedXCMPMessage<u64, u128, u32, u64, u32>;
+
+fn order(device: u64) -> OrderBase<u32, u128, u64, u64> {
+    OrderBase { until: 100, data: 7, fee: 10, device }
+}
+
+#[test]
+fn order_life_cycle() {
+    assert_eq!(DeviceStateMachine::next(Off, E::SwitchOn), Ok(Ready));
+    assert_eq!(DeviceStateMachine::next(Ready, E::Order), Ok(Busy));
+    assert_eq!(DeviceStateMachine::next(Busy, E::Accept), Ok(Accepted));
+    assert_eq!(DeviceStateMachine::next(Accepted, E::Done), Ok(Ready));
+    assert_eq!(DeviceStateMachine::next(Accepted, E::DoneOff), Ok(Off));
+    assert_eq!(DeviceStateMachine::next(Ready, E::SwitchOff), Ok(Off));
+}
+
+#[test]
+fn order_settled_before_acceptance() {
+    // the completion may arrive before the acceptance on the client chain
+    assert_eq!(DeviceStateMachine::next(Busy, E::Done), Ok(Ready));
+    assert_eq!(DeviceStateMachine::next(Busy, E::Reject), Ok(Ready));
+    assert_eq!(DeviceStateMachine::next(Busy, E::RejectOff), Ok(Off));
+    assert_eq!(DeviceStateMachine::next(Busy, E::Cancel), Ok(Ready));
+    assert_eq!(DeviceStateMachine::next(Accepted, E::Cancel), Ok(Ready));
+}
+
+#[test]
+fn switching_requires_no_order() {
+    for from in [Busy, Accepted] {
+        for event in [E::SwitchOn, E::SwitchOff] {
+            assert_eq!(
+                DeviceStateMachine::next(from, event),
+                Err(TransitionError::NotAllowed { from, event })
+            );
+        }
+    }
+}
+
+#[test]
+fn orders_need_a_ready_device() {
+    for from in [Off, Busy, Accepted] {
+        assert_eq!(
+            DeviceStateMachine::next(from, E::Order),
+            Err(TransitionError::NotAllowed { from, event: E::Order })
+        );
+    }
+    assert_eq!(
+        DeviceStateMachine::next(Ready, E::Accept),
+        Err(TransitionError::NotAllowed { from: Ready, event: E::Accept })
+    );
+    assert_eq!(
+        DeviceStateMachine::next(Accepted, E::Accept),
+        Err(TransitionError::NotAllowed { from: Accepted, event: E::Accept })
+    );
+}
+
+#[test]
+fn reaped_idle_device_goes_off() {
+    assert_eq!(DeviceStateMachine::next(Ready, E::Reaped), Ok(Off));
+}
+
+#[test]
+fn reaped_device_goes_off_once_settled() {
+    for from in [Busy, Accepted] {
+        assert_eq!(DeviceStateMachine::next(from, E::Reaped), Ok(Timewait));
+    }
+    for event in [E::Reject, E::RejectOff, E::Done, E::DoneOff, E::Cancel] {
+        assert_eq!(DeviceStateMachine::next(Timewait, event), Ok(Off));
+    }
+}
+
+#[test]
+fn reaped_device_cannot_be_switched() {
+    for event in [E::SwitchOn, E::SwitchOff, E::Order, E::Accept, E::Reaped] {
+        assert_eq!(
+            DeviceStateMachine::next(Timewait, event),
+            Err(TransitionError::Abandoned { event })
+        );
+    }
+}
+
+#[test]
+fn messages_survive_a_round_trip_through_version_1() {
+    let id = H256::repeat_byte(1);
+    for msg in [
+        Message::NewOrder(id, 1, order(2)),
+        Message::OrderAccept(id, 1, 2),
+        Message::OrderReject(id, 1, 2, false),
+        Message::OrderDone(id, 1, 2, true, None),
+    ] {
+        let old = Versioned::from(msg.clone()).into_version(1).unwrap();
+        assert_eq!(old.version(), 1);
+        assert_eq!(old.into_latest(|_, _| id), msg);
+    }
+}
+
+#[test]
+fn version_1_resolves_order_ids_and_drops_results() {
+    let id = H256::repeat_byte(1);
+    let old = Versioned::from(Message::OrderDone(id, 1, 2, true, Some(5)))
+        .into_version(1)
+        .unwrap();
+    assert_eq!(old, Versioned::V1(v1::XCMPMessage::OrderDone(1, 2, true)));
+    let resolved = old.into_latest(|client, device| {
+        assert_eq!((*client, *device), (1, 2));
+        H256::repeat_byte(2)
+    });
+    assert_eq!(resolved, Message::OrderDone(H256::repeat_byte(2), 1, 2, true, None));
+}
+
+#[test]
+fn latest_messages_stay_as_they_are() {
+    let msg = Message::Batch(vec![Message::Ping(1), Message::Version(XCMP_VERSION)]);
+    let versioned = Versioned::from(msg.clone()).into_version(XCMP_VERSION).unwrap();
+    assert_eq!(versioned.version(), XCMP_VERSION);
+    assert_eq!(versioned.into_latest(|_, _| unreachable!()), msg);
+}
+
+#[test]
+fn unsupported_conversions_are_refused() {
+    // version 1 messages carry no order id to upgrade with
+    let old = Versioned::V1(v1::XCMPMessage::OrderAccept(1, 2));
+    assert_eq!(old.into_version(2), Err(()));
+    for version in [0, XCMP_VERSION + 1] {
+        assert_eq!(Versioned::from(Message::Ping(1)).into_version(version), Err(()));
+    }
+    let id = H256::repeat_byte(1);
+    for msg in [
+        Message::OrderProgress(id, 1, 2, Default::default(), None),
+        Message::Version(XCMP_VERSION),
+        Message::Batch(vec![Message::OrderAccept(id, 1, 2)]),
+        Message::Ping(1),
+        Message::Pong(1),
+        Message::OrderExpired(id, 1, 2),
+        Message::DeviceRegistered(2, 10, 5, true, Default::default()),
+        Message::DeviceUpdated(2, 10, 5, false, Default::default()),
+        Message::DeviceRemoved(2),
+        Message::CancelOrder(id, 1, 2),
+        Message::OrderCancelled(id, 1, 2, 3),
+        Message::DeviceSchemas(2, vec![1]),
+    ] {
+        assert_eq!(Versioned::from(msg).into_version(1), Err(()));
+    }
+}
+
+#[test]
+fn order_ids_are_deterministic() {
+    assert_eq!(order_id(&1u64, &2u64, 0, &2000u32), order_id(&1u64, &2u64, 0, &2000u32));
+}
+
+#[test]
+fn order_ids_differ_by_client_device_nonce_and_chain() {
+    let id = order_id(&1u64, &2u64, 0, &2000u32);
+    for other in [
+        order_id(&3u64, &2u64, 0, &2000u32),
+        order_id(&1u64, &3u64, 0, &2000u32),
+        // the client and the device don't swap roles
+        order_id(&2u64, &1u64, 0, &2000u32),
+        order_id(&1u64, &2u64, 1, &2000u32),
+        order_id(&1u64, &2u64, 0, &2001u32),
+    ] {
+        assert_ne!(id, other);
+    }
+}
+
+#[test]
+fn encoded_messages_start_with_their_version() {
+    let msg = Versioned::from(Message::Ping(1));
+    assert_eq!(Versioned::peek_version(&msg.encode()), Some(XCMP_VERSION));
+    assert_eq!(Versioned::decode_limited(&msg.encode()), Ok(msg));
+    let msg = Versioned::V1(v1::XCMPMessage::OrderAccept(1, 2));
+    assert_eq!(Versioned::peek_version(&msg.encode()), Some(1));
+}
+
+#[test]
+fn batches_nested_too_deep_are_refused() {
+    let nested = |depth| (0..depth).fold(Message::Ping(1), |msg, _| Message::Batch(vec![msg]));
+    let shallow = Versioned::from(nested(4)).encode();
+    assert!(Versioned::decode_limited(&shallow).is_ok());
+    let deep = Versioned::from(nested(MAX_XCMP_DECODE_DEPTH + 1)).encode();
+    assert!(Versioned::decode_limited(&deep).is_err());
+}
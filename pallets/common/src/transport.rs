@@ -0,0 +1,739 @@
+//! Delivery of protocol messages between the client and the service pallets: version
+//! negotiation, execution fees on partner chains, the outbox of undelivered messages, the
+//! queue of overweight inbound messages and transfers to accounts of partner chains.
+
+use super::*;
+use frame_support::{
+    dispatch::{DispatchError, DispatchResult, DispatchResultWithPostInfo},
+    sp_runtime::traits::{Convert, One, SaturatedConversion, Saturating, Zero},
+    storage::{StorageMap, StorageValue},
+    traits::{
+        Currency, ExistenceRequirement, Get, OnUnbalanced, ReservableCurrency, WithdrawReasons,
+    },
+    weights::{RuntimeDbWeight, Weight},
+    Parameter,
+};
+use xcm::latest::prelude::*;
+use xcm::latest::MultiLocation;
+use xcm::WrapVersion;
+use xcm_executor::traits::{Convert as XcmConvert, InvertLocation};
+
+pub type AccountIdOf<S> = <<S as Transport>::Runtime as frame_system::Config>::AccountId;
+pub type BlockNumberOf<S> = <<S as Transport>::Runtime as frame_system::Config>::BlockNumber;
+pub type BalanceOf<S> = <<S as Transport>::Currency as Currency<AccountIdOf<S>>>::Balance;
+pub type NegativeImbalanceOf<S> =
+    <<S as Transport>::Currency as Currency<AccountIdOf<S>>>::NegativeImbalance;
+pub type MessageOf<S> = XCMPMessage<
+    AccountIdOf<S>,
+    BalanceOf<S>,
+    <S as Transport>::Payload,
+    <S as Transport>::Moment,
+    <S as Transport>::OrderResult,
+>;
+pub type VersionedMessageOf<S> = VersionedXCMPMessage<
+    AccountIdOf<S>,
+    BalanceOf<S>,
+    <S as Transport>::Payload,
+    <S as Transport>::Moment,
+    <S as Transport>::OrderResult,
+>;
+pub type PendingTransferOf<S> = PendingTransfer<AccountIdOf<S>, BalanceOf<S>>;
+
+/// Instructions of the longest transfer program, the one going through the relay chain.
+pub const MAX_TRANSFER_INSTRUCTIONS: Weight = 6;
+
+/// Protocol message waiting in the outbox for a delivery retry.
+#[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo)]
+pub struct OutboundMessage<Message> {
+    pub dest: MultiLocation,
+    pub message: Message,
+    pub attempts: u32,
+}
+
+/// Transfer to a partner chain waiting for spare block weight.
+#[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo)]
+pub enum PendingTransfer<AccountId, Balance> {
+    /// Pays `amount`, held from `from` for order `id`, to `to` living at `location`
+    Settlement {
+        id: OrderId,
+        from: AccountId,
+        to: AccountId,
+        location: MultiLocation,
+        amount: Balance,
+    },
+    /// Refills our sovereign account on the partner chain `dest` with what it has spent
+    TopUp { dest: MultiLocation },
+}
+
+/// Why a message or a transfer can't leave this chain
+#[derive(Clone, Copy, RuntimeDebug, PartialEq, Eq)]
+pub enum TransportError {
+    /// The destination can't be reached or paid in
+    CannotReachDestination,
+    /// The message can't be expressed in the protocol version of the destination
+    UnsupportedVersion,
+    /// The program can't be expressed in the XCM version of the destination
+    BadXcmVersion,
+    /// The transfer program failed
+    TransferFailed,
+    /// No parked message has the index
+    UnknownOverweight,
+    /// The parked message needs more weight than allowed
+    WeightOverLimit,
+}
+
+/// What the transport reports, deposited as events of the pallet using it.
+#[derive(Clone, RuntimeDebug, PartialEq)]
+pub enum TransportEvent<AccountId, Balance> {
+    /// A message can't be expressed in the XCM version of its destination. [encoded message]
+    BadVersion(Vec<u8>),
+    /// [partner, version]
+    PartnerVersionChanged(MultiLocation, u32),
+    /// A message waits in the outbox for a retry. [destination, outbox id]
+    DeliveryFailed(MultiLocation, u64),
+    /// A message of the outbox is sent. [destination, outbox id]
+    Delivered(MultiLocation, u64),
+    /// A message of the outbox ran out of retries. [destination, outbox id]
+    DeliveryAbandoned(MultiLocation, u64),
+    /// [sender, index, weight]
+    OverweightEnqueued(MultiLocation, u64, Weight),
+    /// [index, weight]
+    OverweightServiced(u64, Weight),
+    /// Our sovereign account on a partner chain is refilled from the message fund.
+    /// [partner, amount]
+    SovereignToppedUp(MultiLocation, Balance),
+    /// What an order owes is sent to its payee on the payee's chain. [order, payee, amount]
+    Settled(OrderId, AccountId, Balance),
+    /// Sending what an order owes failed, the payee is paid on this chain. [order]
+    SettlementFailed(OrderId),
+}
+
+/// Storage, configuration and hooks of a pallet exchanging protocol messages with partner
+/// chains. The provided methods carry the messages.
+pub trait Transport: Sized {
+    type Runtime: frame_system::Config;
+    type Currency: ReservableCurrency<AccountIdOf<Self>>;
+    type Payload: Parameter;
+    type Moment: Parameter;
+    type OrderResult: Parameter;
+    type Error: From<TransportError> + Into<DispatchError>;
+
+    type XcmpMessageSender: SendXcm;
+    type LocationToAccountId: XcmConvert<MultiLocation, AccountIdOf<Self>>;
+    type LocationInverter: InvertLocation;
+    type VersionWrapper: WrapVersion;
+    type FeeAsset: Get<MultiLocation>;
+    type FeeAmount: Convert<Weight, u128>;
+    type XcmInstructionWeight: Get<Weight>;
+    type OnXcmFee: OnUnbalanced<NegativeImbalanceOf<Self>>;
+    type MessageFund: Get<AccountIdOf<Self>>;
+    type XcmExecutor: ExecuteXcm<<Self::Runtime as frame_system::Config>::Call>;
+    type AccountIdToMultiLocation: Convert<AccountIdOf<Self>, MultiLocation>;
+    type SettlementAsset: Get<MultiLocation>;
+    type MaxDeliveryAttempts: Get<u32>;
+    type DeliveryBackoff: Get<BlockNumberOf<Self>>;
+
+    type PartnerVersion: StorageMap<MultiLocation, u32, Query = Option<u32>>;
+    type Outbox: StorageMap<
+        u64,
+        OutboundMessage<MessageOf<Self>>,
+        Query = Option<OutboundMessage<MessageOf<Self>>>,
+    >;
+    type OutboxSchedule: StorageMap<BlockNumberOf<Self>, Vec<u64>, Query = Vec<u64>>;
+    type OutboxNonce: StorageValue<u64, Query = u64>;
+    type Overweight: StorageMap<
+        u64,
+        (MultiLocation, VersionedMessageOf<Self>),
+        Query = Option<(MultiLocation, VersionedMessageOf<Self>)>,
+    >;
+    type OverweightRange: StorageValue<(u64, u64), Query = (u64, u64)>;
+    type SovereignSpent: StorageMap<MultiLocation, BalanceOf<Self>, Query = BalanceOf<Self>>;
+    type Transfers: StorageMap<
+        u64,
+        PendingTransferOf<Self>,
+        Query = Option<PendingTransferOf<Self>>,
+    >;
+    type TransferRange: StorageValue<(u64, u64), Query = (u64, u64)>;
+
+    /// Instructions of the program carrying a message, besides the call.
+    const PROGRAM_INSTRUCTIONS: Weight;
+
+    /// Index of the partner pallet and of its call receiving our messages.
+    fn receive_call_index() -> (u8, u8);
+
+    fn deposit_transport_event(event: TransportEvent<AccountIdOf<Self>, BalanceOf<Self>>);
+
+    /// Weight of applying `msg`, known before it is applied.
+    fn message_weight(msg: &VersionedMessageOf<Self>) -> Weight;
+
+    /// Applies a protocol message of any supported version from `sender`.
+    fn receive(sender: &MultiLocation, msg: VersionedMessageOf<Self>) -> DispatchResult;
+
+    /// Settles what was held for a message which can't be delivered anymore.
+    fn on_undelivered(msg: MessageOf<Self>);
+
+    /// Whether `msg`, waiting in the outbox, has been overtaken by events and mustn't be sent.
+    fn is_stale(_msg: &MessageOf<Self>) -> bool {
+        false
+    }
+
+    /// Called once the protocol version negotiated with `partner` has changed from `old`.
+    fn on_version_changed(_partner: &MultiLocation, _old: Option<u32>, _new: u32) {}
+
+    fn db_weight() -> RuntimeDbWeight {
+        <Self::Runtime as frame_system::Config>::DbWeight::get()
+    }
+
+    fn error(e: TransportError) -> DispatchError {
+        Self::Error::from(e).into()
+    }
+
+    /// Protocol version to talk to `dest` with. A partner we haven't heard from is assumed to
+    /// be up to date, until it sends us a message of an older version.
+    fn partner_version_or_default(dest: &MultiLocation) -> u32 {
+        Self::PartnerVersion::get(dest).unwrap_or(XCMP_VERSION)
+    }
+
+    /// Records that `sender` has sent us a message of `version`. Our own version is announced
+    /// on first contact.
+    fn note_version(sender: &MultiLocation, version: u32) {
+        let known = Self::PartnerVersion::get(sender);
+        if known != Some(version.min(XCMP_VERSION)) {
+            Self::set_partner_version(sender, version);
+        }
+        if known.is_none() {
+            Self::announce_version(sender);
+        }
+    }
+
+    fn set_partner_version(partner: &MultiLocation, version: u32) {
+        let version = version.min(XCMP_VERSION);
+        let old = Self::PartnerVersion::get(partner);
+        Self::PartnerVersion::insert(partner, version);
+        Self::deposit_transport_event(TransportEvent::PartnerVersionChanged(
+            partner.clone(),
+            version,
+        ));
+        if old != Some(version) {
+            Self::on_version_changed(partner, old, version);
+        }
+    }
+
+    /// Pins the protocol version of `partner`, or forgets it to negotiate it again.
+    fn force_version(partner: &MultiLocation, version: Option<u32>) -> Result<(), TransportError> {
+        match version {
+            Some(version) => {
+                if !(MIN_XCMP_VERSION..=XCMP_VERSION).contains(&version) {
+                    return Err(TransportError::UnsupportedVersion);
+                }
+                Self::set_partner_version(partner, version);
+            },
+            None => Self::PartnerVersion::remove(partner),
+        }
+        Ok(())
+    }
+
+    fn announce_version(dest: &MultiLocation) {
+        let msg = VersionedMessageOf::<Self>::from(MessageOf::<Self>::Version(XCMP_VERSION));
+        if let Err(e) = Self::send_versioned(dest, msg, None) {
+            log::warn!("version announcement to {:?} failed: {:?}", dest, e);
+        }
+    }
+
+    /// Sends `msg` to `dest`, keeping it in the outbox if it can't be delivered now.
+    fn deliver(dest: &MultiLocation, msg: MessageOf<Self>) {
+        if let Err(e) = Self::try_send(dest, msg.clone()) {
+            if e == TransportError::UnsupportedVersion {
+                log::warn!("message to {:?} dropped, the partner can't understand it", dest);
+                Self::on_undelivered(msg);
+                return;
+            }
+            if e == TransportError::BadXcmVersion {
+                Self::deposit_transport_event(TransportEvent::BadVersion(msg.encode()));
+            }
+            log::warn!("message to {:?} is kept in the outbox: {:?}", dest, e);
+            let id = Self::OutboxNonce::mutate(|n| {
+                *n = n.wrapping_add(1);
+                *n
+            });
+            Self::Outbox::insert(
+                id,
+                OutboundMessage { dest: dest.clone(), message: msg, attempts: 1 },
+            );
+            let now = frame_system::Pallet::<Self::Runtime>::block_number();
+            Self::schedule_retry(id, now, 1);
+            Self::deposit_transport_event(TransportEvent::DeliveryFailed(dest.clone(), id));
+        }
+    }
+
+    fn schedule_retry(id: u64, now: BlockNumberOf<Self>, attempts: u32) {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        let delay = Self::DeliveryBackoff::get().saturating_mul(factor.into()).max(One::one());
+        Self::OutboxSchedule::append(now.saturating_add(delay), id);
+    }
+
+    /// Retries the outbox messages due at `now`.
+    fn retry_due(now: BlockNumberOf<Self>) -> Weight {
+        let due = Self::OutboxSchedule::take(now);
+        let count = due.len() as Weight;
+        for id in due {
+            Self::retry_message(id, now);
+        }
+        // the entry, the staleness check and the delivery
+        Self::db_weight().reads_writes(1 + 4 * count, 1 + 2 * count)
+    }
+
+    fn retry_message(id: u64, now: BlockNumberOf<Self>) {
+        let mut entry = match Self::Outbox::get(id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if Self::is_stale(&entry.message) {
+            log::debug!("stale message {} to {:?} dropped", id, entry.dest);
+            Self::Outbox::remove(id);
+            return;
+        }
+        if Self::try_send(&entry.dest, entry.message.clone()).is_ok() {
+            Self::Outbox::remove(id);
+            Self::deposit_transport_event(TransportEvent::Delivered(entry.dest, id));
+            return;
+        }
+        entry.attempts += 1;
+        if entry.attempts >= Self::MaxDeliveryAttempts::get() {
+            Self::Outbox::remove(id);
+            Self::deposit_transport_event(TransportEvent::DeliveryAbandoned(
+                entry.dest.clone(),
+                id,
+            ));
+            Self::on_undelivered(entry.message);
+        } else {
+            Self::schedule_retry(id, now, entry.attempts);
+            Self::deposit_transport_event(TransportEvent::DeliveryFailed(entry.dest.clone(), id));
+            Self::Outbox::insert(id, entry);
+        }
+    }
+
+    /// Sends `msg` to `dest`, down-converted to the version negotiated with it.
+    fn try_send(dest: &MultiLocation, msg: MessageOf<Self>) -> Result<(), TransportError> {
+        let msg = Self::versioned_for(dest, msg)?;
+        Self::send_versioned(dest, msg, None)
+    }
+
+    /// Converts `msg` into the version negotiated with `dest`.
+    fn versioned_for(
+        dest: &MultiLocation,
+        msg: MessageOf<Self>,
+    ) -> Result<VersionedMessageOf<Self>, TransportError> {
+        let version = Self::partner_version_or_default(dest);
+        VersionedMessageOf::<Self>::from(msg)
+            .into_version(version)
+            .map_err(|_| TransportError::UnsupportedVersion)
+    }
+
+    /// Sends `msg` to `dest` in a program paid by our sovereign account there. `appendix` runs
+    /// once the call is dispatched, whatever its outcome.
+    fn send_versioned(
+        dest: &MultiLocation,
+        msg: VersionedMessageOf<Self>,
+        appendix: Option<Xcm<()>>,
+    ) -> Result<(), TransportError> {
+        // this chain as the destination sees it
+        let here = Self::LocationInverter::invert_location(dest)
+            .map_err(|_| TransportError::CannotReachDestination)?;
+        let weight = msg.receive_weight();
+        let fee = MultiAsset {
+            id: Concrete(Self::FeeAsset::get()),
+            fun: Fungible(Self::FeeAmount::convert(Self::program_weight(weight))),
+        }
+        .reanchored(&here)
+        .map_err(|_| TransportError::CannotReachDestination)?;
+        let (pallet_index, call_index) = Self::receive_call_index();
+        // the receiving call takes the encoded message, to report what it can't decode
+        let call = (pallet_index, call_index, msg.encode()).encode();
+        let mut message = Xcm(vec![
+            Instruction::WithdrawAsset(fee.clone().into()),
+            Instruction::BuyExecution {
+                fees: fee,
+                weight_limit: WeightLimit::Limited(Self::program_weight(weight)),
+            },
+            // dispatched from our sovereign account there, which the partner converts back
+            Instruction::Transact {
+                origin_type: OriginKind::SovereignAccount,
+                require_weight_at_most: weight,
+                call: call.into(),
+            },
+            Instruction::RefundSurplus,
+            // what is left goes back to our sovereign account there
+            Instruction::DepositAsset {
+                assets: MultiAssetFilter::Wild(WildMultiAsset::All),
+                max_assets: 1,
+                beneficiary: here,
+            },
+        ]);
+        if let Some(appendix) = appendix {
+            // after the paid execution is bought, the barrier wants it first
+            message.0.insert(2, Instruction::SetAppendix(appendix));
+        }
+        // the transport wraps the program the same way, it is kept for a retry while the
+        // version of the partner is unknown
+        let message: Xcm<()> = Self::VersionWrapper::wrap_version(dest, message)
+            .and_then(|versioned| versioned.try_into())
+            .map_err(|_| TransportError::BadXcmVersion)?;
+        Self::XcmpMessageSender::send_xcm(dest.clone(), message)
+            .map_err(|_| TransportError::CannotReachDestination)?;
+        Self::note_execution_spent(dest, weight);
+        Ok(())
+    }
+
+    /// Weight of the program carrying a call of `call_weight` to a partner chain.
+    fn program_weight(call_weight: Weight) -> Weight {
+        Self::XcmInstructionWeight::get()
+            .saturating_mul(Self::PROGRAM_INSTRUCTIONS)
+            .saturating_add(call_weight)
+    }
+
+    /// What executing a single message on a partner chain costs, in the local currency.
+    fn message_fee() -> BalanceOf<Self> {
+        Self::FeeAmount::convert(Self::program_weight(RECEIVE_MESSAGE_WEIGHT)).saturated_into()
+    }
+
+    /// Charges `payer` for the execution on a partner chain of a message it triggers.
+    fn charge_message_fee(payer: &AccountIdOf<Self>) -> DispatchResult {
+        let fee = Self::Currency::withdraw(
+            payer,
+            Self::message_fee(),
+            WithdrawReasons::FEE,
+            ExistenceRequirement::KeepAlive,
+        )?;
+        Self::OnXcmFee::on_unbalanced(fee);
+        Ok(())
+    }
+
+    /// Adds the execution fee of a message sent to `dest` to what our sovereign account there
+    /// has spent. Each time that grows by a transfer's worth, a refill from the message fund
+    /// is queued.
+    fn note_execution_spent(dest: &MultiLocation, weight: Weight) {
+        let fee: BalanceOf<Self> =
+            Self::FeeAmount::convert(Self::program_weight(weight)).saturated_into();
+        let threshold = Self::message_fee().saturating_mul(TOP_UP_MESSAGES.into());
+        let (before, after) = Self::SovereignSpent::mutate(dest, |spent| {
+            let before = *spent;
+            *spent = spent.saturating_add(fee);
+            (before, *spent)
+        });
+        if threshold.is_zero() || before / threshold >= after / threshold {
+            return;
+        }
+        Self::enqueue_transfer(PendingTransfer::TopUp { dest: dest.clone() });
+    }
+
+    /// Refills our sovereign account on `dest` from the message fund with what it has spent.
+    fn top_up_sovereign(dest: &MultiLocation) {
+        let spent = Self::SovereignSpent::get(dest);
+        if spent.is_zero() {
+            return;
+        }
+        // our sovereign account as the partner sees it
+        let sovereign = match Self::LocationInverter::invert_location(dest) {
+            Ok(here) => here,
+            Err(_) => return,
+        };
+        match Self::transfer_asset(
+            &Self::MessageFund::get(),
+            Self::FeeAsset::get(),
+            dest,
+            sovereign,
+            spent,
+        ) {
+            Ok(()) => {
+                Self::SovereignSpent::remove(dest);
+                Self::deposit_transport_event(TransportEvent::SovereignToppedUp(
+                    dest.clone(),
+                    spent,
+                ));
+            },
+            Err(e) => log::warn!("cannot refill our sovereign account on {:?}: {:?}", dest, e),
+        }
+    }
+
+    /// Moves `amount` of the free balance of `from` to `to` living at `location`. An account
+    /// junction ending `location` receives the funds instead of the location of `to`.
+    fn reserve_transfer(
+        from: &AccountIdOf<Self>,
+        to: &AccountIdOf<Self>,
+        location: &MultiLocation,
+        amount: BalanceOf<Self>,
+    ) -> Result<(), TransportError> {
+        let beneficiary = account_junction(location)
+            .map(MultiLocation::from)
+            .unwrap_or_else(|| Self::AccountIdToMultiLocation::convert(to.clone()));
+        Self::transfer_asset(
+            from,
+            Self::SettlementAsset::get(),
+            &chain_location(location),
+            beneficiary,
+            amount,
+        )
+    }
+
+    /// Moves `amount` of `asset` from the free balance of `from` to `beneficiary` on `dest`.
+    /// An asset this chain is the reserve of goes straight to the destination, one of the
+    /// relay chain goes through the relay chain, the reserve both chains trust.
+    fn transfer_asset(
+        from: &AccountIdOf<Self>,
+        asset: MultiLocation,
+        dest: &MultiLocation,
+        beneficiary: MultiLocation,
+        amount: BalanceOf<Self>,
+    ) -> Result<(), TransportError> {
+        let reserve = asset.clone();
+        let asset = MultiAsset { id: Concrete(asset), fun: Fungible(amount.saturated_into()) };
+        // the asset as the destination sees it
+        let fees = Self::LocationInverter::invert_location(dest)
+            .and_then(|here| asset.clone().reanchored(&here))
+            .map_err(|_| TransportError::TransferFailed)?;
+        let deposit = Xcm(vec![
+            Instruction::BuyExecution { fees, weight_limit: WeightLimit::Unlimited },
+            Instruction::DepositAsset {
+                assets: MultiAssetFilter::Wild(WildMultiAsset::All),
+                max_assets: 1,
+                beneficiary,
+            },
+        ]);
+        let (message, instructions) = match reserve {
+            MultiLocation { parents: 0, .. } => (
+                Xcm(vec![Instruction::TransferReserveAsset {
+                    assets: asset.into(),
+                    dest: dest.clone(),
+                    xcm: deposit,
+                }]),
+                3,
+            ),
+            reserve if reserve == MultiLocation::parent() => {
+                // the destination and the asset as the relay chain sees them
+                let here = Self::LocationInverter::invert_location(&reserve)
+                    .map_err(|_| TransportError::TransferFailed)?;
+                let relay_dest =
+                    dest.clone().reanchored(&here).map_err(|_| TransportError::TransferFailed)?;
+                let relay_fees =
+                    asset.clone().reanchored(&here).map_err(|_| TransportError::TransferFailed)?;
+                (
+                    Xcm(vec![
+                        Instruction::WithdrawAsset(asset.into()),
+                        Instruction::InitiateReserveWithdraw {
+                            assets: MultiAssetFilter::Wild(WildMultiAsset::All),
+                            reserve,
+                            xcm: Xcm(vec![
+                                Instruction::BuyExecution {
+                                    fees: relay_fees,
+                                    weight_limit: WeightLimit::Unlimited,
+                                },
+                                Instruction::DepositReserveAsset {
+                                    assets: MultiAssetFilter::Wild(WildMultiAsset::All),
+                                    max_assets: 1,
+                                    dest: relay_dest,
+                                    xcm: deposit,
+                                },
+                            ]),
+                        },
+                    ]),
+                    MAX_TRANSFER_INSTRUCTIONS,
+                )
+            },
+            // no reserve the destination trusts
+            _ => return Err(TransportError::TransferFailed),
+        };
+        let weight = Self::XcmInstructionWeight::get().saturating_mul(instructions);
+        let origin = Self::AccountIdToMultiLocation::convert(from.clone());
+        match Self::XcmExecutor::execute_xcm_in_credit(origin, message, weight, weight) {
+            Outcome::Complete(_) => Ok(()),
+            outcome => {
+                log::warn!("reserve transfer to {:?} failed: {:?}", dest, outcome);
+                Err(TransportError::TransferFailed)
+            },
+        }
+    }
+
+    /// Queues the settlement of order `id`: `amount`, held from `from`, goes to `to` living at
+    /// `location` once a block has weight to spare for the transfer.
+    fn settle_later(
+        id: OrderId,
+        from: &AccountIdOf<Self>,
+        to: &AccountIdOf<Self>,
+        location: &MultiLocation,
+        amount: BalanceOf<Self>,
+    ) {
+        Self::enqueue_transfer(PendingTransfer::Settlement {
+            id,
+            from: from.clone(),
+            to: to.clone(),
+            location: location.clone(),
+            amount,
+        });
+    }
+
+    fn enqueue_transfer(transfer: PendingTransferOf<Self>) {
+        let index = Self::TransferRange::mutate(|(_, next)| {
+            let index = *next;
+            *next = next.wrapping_add(1);
+            index
+        });
+        Self::Transfers::insert(index, transfer);
+    }
+
+    /// Weight of executing a pending transfer: the longest transfer program and the balance
+    /// changes around it.
+    fn transfer_weight() -> Weight {
+        Self::XcmInstructionWeight::get()
+            .saturating_mul(MAX_TRANSFER_INSTRUCTIONS)
+            .saturating_add(Self::db_weight().reads_writes(3, 3))
+    }
+
+    fn execute_transfer(transfer: PendingTransferOf<Self>) {
+        match transfer {
+            PendingTransfer::Settlement { id, from, to, location, amount } => {
+                Self::Currency::unreserve(&from, amount);
+                match Self::reserve_transfer(&from, &to, &location, amount) {
+                    Ok(()) => {
+                        Self::deposit_transport_event(TransportEvent::Settled(id, to, amount))
+                    },
+                    Err(_) => {
+                        if let Err(e) = Self::Currency::transfer(
+                            &from,
+                            &to,
+                            amount,
+                            ExistenceRequirement::AllowDeath,
+                        ) {
+                            log::warn!("order {:?} can't be settled: {:?}", id, e);
+                        }
+                        Self::deposit_transport_event(TransportEvent::SettlementFailed(id));
+                    },
+                }
+            },
+            PendingTransfer::TopUp { dest } => Self::top_up_sovereign(&dest),
+        }
+    }
+
+    /// Executes pending transfers in queue order while they fit in `limit`.
+    fn service_transfer_queue(limit: Weight) -> Weight {
+        let db = Self::db_weight();
+        let (mut head, next) = Self::TransferRange::get();
+        let mut used = db.reads(1);
+        if head == next {
+            return used;
+        }
+        used = used.saturating_add(db.writes(1));
+        let weight = Self::transfer_weight().saturating_add(db.reads_writes(1, 1));
+        while head != next && used.saturating_add(weight) <= limit {
+            if let Some(transfer) = Self::Transfers::take(head) {
+                Self::execute_transfer(transfer);
+            }
+            used = used.saturating_add(weight);
+            head = head.wrapping_add(1);
+        }
+        Self::TransferRange::mutate(|(oldest, _)| *oldest = head);
+        used
+    }
+
+    /// Location of the partner chain whose sovereign account dispatched a call.
+    fn partner_location(who: &AccountIdOf<Self>) -> Result<MultiLocation, DispatchError> {
+        match Self::LocationToAccountId::reverse_ref(who) {
+            Ok(location) if is_foreign(&location) => Ok(location),
+            _ => Err(DispatchError::BadOrigin),
+        }
+    }
+
+    /// Receives an encoded protocol message a partner chain sent with the sovereign account
+    /// `who`. A message of a version we don't know, or malformed, is reported and dropped.
+    fn receive_message(who: &AccountIdOf<Self>, data: &[u8]) -> DispatchResultWithPostInfo {
+        let sender = Self::partner_location(who)?;
+        let msg = match VersionedMessageOf::<Self>::decode_limited(data) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::warn!(
+                    "message of version {:?} from {:?} can't be decoded: {:?}",
+                    VersionedMessageOf::<Self>::peek_version(data),
+                    sender,
+                    e,
+                );
+                Self::deposit_transport_event(TransportEvent::BadVersion(data.to_vec()));
+                return Ok(Some(Self::db_weight().writes(1)).into());
+            },
+        };
+        let weight = Self::message_weight(&msg);
+        // the sender bought too little weight, the message waits for spare block weight
+        if weight > msg.receive_weight() {
+            Self::park_overweight(&sender, msg, weight);
+            return Ok(Some(Self::db_weight().writes(2)).into());
+        }
+        Self::receive(&sender, msg)?;
+        Ok(Some(weight).into())
+    }
+
+    /// Parks a message which doesn't fit in the weight left for inbound messages or in the
+    /// weight its sender paid for.
+    fn park_overweight(sender: &MultiLocation, msg: VersionedMessageOf<Self>, weight: Weight) {
+        let index = Self::OverweightRange::mutate(|(_, next)| {
+            let index = *next;
+            *next = next.wrapping_add(1);
+            index
+        });
+        Self::Overweight::insert(index, (sender.clone(), msg));
+        Self::deposit_transport_event(TransportEvent::OverweightEnqueued(
+            sender.clone(),
+            index,
+            weight,
+        ));
+    }
+
+    fn apply_overweight(
+        index: u64,
+        sender: &MultiLocation,
+        msg: VersionedMessageOf<Self>,
+        weight: Weight,
+    ) {
+        if let Err(e) = Self::receive(sender, msg) {
+            log::warn!("parked message {} from {:?} failed: {:?}", index, sender, e);
+        }
+        Self::deposit_transport_event(TransportEvent::OverweightServiced(index, weight));
+    }
+
+    /// Applies the parked message `index`, allowing it up to `weight_limit`.
+    fn service_parked(index: u64, weight_limit: Weight) -> DispatchResultWithPostInfo {
+        let (sender, msg) = Self::Overweight::get(index)
+            .ok_or_else(|| Self::error(TransportError::UnknownOverweight))?;
+        let weight = Self::message_weight(&msg);
+        if weight > weight_limit {
+            return Err(Self::error(TransportError::WeightOverLimit).into());
+        }
+        Self::Overweight::remove(index);
+        Self::apply_overweight(index, &sender, msg, weight);
+        Ok(Some(weight.saturating_add(10_000)).into())
+    }
+
+    /// Applies parked messages in arrival order while they fit in `limit`.
+    fn service_overweight_queue(limit: Weight) -> Weight {
+        let db = Self::db_weight();
+        let (mut head, next) = Self::OverweightRange::get();
+        let mut used = db.reads(1);
+        if head == next {
+            return used;
+        }
+        used = used.saturating_add(db.writes(1));
+        while head != next && used.saturating_add(db.reads(1)) <= limit {
+            used = used.saturating_add(db.reads(1));
+            if let Some((sender, msg)) = Self::Overweight::get(head) {
+                let weight = Self::message_weight(&msg).saturating_add(db.writes(1));
+                if used.saturating_add(weight) > limit {
+                    break;
+                }
+                Self::Overweight::remove(head);
+                Self::apply_overweight(head, &sender, msg, weight);
+                used = used.saturating_add(weight);
+            }
+            head = head.wrapping_add(1);
+        }
+        Self::OverweightRange::mutate(|(oldest, _)| *oldest = head);
+        used
+    }
+}
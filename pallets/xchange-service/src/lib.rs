@@ -7,10 +7,12 @@
 use codec::{Decode, Encode};
 use frame_support::weights::Weight;
 use frame_support::{
-	dispatch::DispatchResult,
-	sp_runtime::traits::Hash,
-	sp_runtime::RuntimeDebug,
-	traits::{BalanceStatus::Free, Currency, Get, ReservableCurrency},
+	dispatch::{DispatchError, DispatchResult},
+	sp_runtime::traits::{Convert, Hash, One, Saturating, Zero},
+	sp_runtime::{Perbill, Percent, RuntimeDebug, TransactionOutcome},
+	storage::with_transaction,
+	traits::{BalanceStatus::Free, Currency, Get, OnUnbalanced, ReservableCurrency},
+	BoundedVec,
 };
 
 use xcm::latest::{prelude::*, Junction, MultiLocation, OriginKind, SendXcm, Xcm};
@@ -27,10 +29,14 @@ use sp_std::convert::{TryFrom, TryInto};
 
 use cumulus_primitives_core::{
 	relay_chain, relay_chain::BlockNumber as RelayBlockNumber, ParaId, ServiceQuality,
-	XcmpMessageFormat, XcmpMessageHandler,
 };
 
-use xcm::VersionedXcm;
+use xcm::{VersionedXcm, WrapVersion};
+use xcm_executor::traits::{Convert as XcmConvert, InvertLocation};
+
+use pallet_common::transport::{
+	OutboundMessage, PendingTransfer, Transport, TransportError, TransportEvent,
+};
 
 pub trait OnReceived<T: Config> {
 	fn on_received(
@@ -39,11 +45,20 @@ pub trait OnReceived<T: Config> {
 	) -> Option<DeviceState>;
 }
 
-type XCMPMessageOf<T> = XCMPMessage<
+pub type XCMPMessageOf<T> = XCMPMessage<
 	<T as frame_system::Config>::AccountId,
 	BalanceOf<T>,
 	<T as Config>::OrderPayload,
 	<T as pallet_timestamp::Config>::Moment,
+	<T as Config>::OrderResult,
+>;
+
+pub type VersionedXCMPMessageOf<T> = VersionedXCMPMessage<
+	<T as frame_system::Config>::AccountId,
+	BalanceOf<T>,
+	<T as Config>::OrderPayload,
+	<T as pallet_timestamp::Config>::Moment,
+	<T as Config>::OrderResult,
 >;
 
 pub type OrderBaseOf<T> = OrderBase<
@@ -58,15 +73,20 @@ pub type OrderOf<T> = Order<
 	BalanceOf<T>,
 	MomentOf<T>,
 	<T as frame_system::Config>::AccountId,
-	ParaId,
+	MultiLocation,
 >;
 
 pub type BalanceOf<T> =
 	<<T as Config>::Currency as Currency<<T as frame_system::Config>::AccountId>>::Balance;
 pub type MomentOf<T> = <T as pallet_timestamp::Config>::Moment;
+pub type NegativeImbalanceOf<T> = <<T as Config>::Currency as Currency<
+	<T as frame_system::Config>::AccountId,
+>>::NegativeImbalance;
 
 type Timestamp<T> = pallet_timestamp::Pallet<T>;
 
+pub mod migrations;
+
 #[frame_support::pallet]
 pub mod pallet {
 	use super::*;
@@ -81,12 +101,68 @@ pub mod pallet {
 
 		type Currency: ReservableCurrency<Self::AccountId>;
 
-		type OrderPayload: Encode + Decode + Clone + Default + Parameter + TypeInfo;
+		type OrderPayload: Encode + Decode + Clone + Default + Parameter + TypeInfo + PayloadSchema;
+		/// What a device hands back to the client with a finished order
+		type OrderResult: Encode + Decode + Clone + Parameter + TypeInfo + MaxEncodedLen;
 		type SelfParaId: Get<ParaId>;
 
 		type XcmpMessageSender: SendXcm;
+		/// Converts partner locations to their sovereign accounts here and back, the calls
+		/// partners send us are dispatched from these accounts
+		type LocationToAccountId: XcmConvert<MultiLocation, Self::AccountId>;
+		/// Finds the location of this chain seen from a partner chain
+		type LocationInverter: InvertLocation;
+		/// Picks the XCM version our programs are sent to a partner in, it asks partners of
+		/// unknown version for theirs meanwhile
+		type VersionWrapper: WrapVersion;
+		/// Index of `pallet_xchange` in the runtime of client chains
+		type ClientPalletIndex: Get<u8>;
+
+		/// Asset paying for the execution of our messages on partner chains
+		type FeeAsset: Get<MultiLocation>;
+		/// Amount of the fee asset buying the given weight on partner chains
+		type FeeAmount: Convert<Weight, u128>;
+		/// Weight of one XCM instruction on partner chains
+		type XcmInstructionWeight: Get<Weight>;
+		/// Receives the fees charged to the accounts triggering messages. They should end up
+		/// in `MessageFund`
+		type OnXcmFee: OnUnbalanced<NegativeImbalanceOf<Self>>;
+		/// Account refilling our sovereign accounts on partner chains, which pay for the
+		/// execution of every message we send, including the ones no account triggers
+		type MessageFund: Get<Self::AccountId>;
+
+		/// Executes the transfers settling orders with other chains
+		type XcmExecutor: ExecuteXcm<<Self as frame_system::Config>::Call>;
+		/// Location of a local account, the same on partner chains for their accounts
+		type AccountIdToMultiLocation: Convert<Self::AccountId, MultiLocation>;
+		/// Asset of `Currency` as partner chains see it. Either this chain or the relay chain
+		/// acts as its reserve
+		type SettlementAsset: Get<MultiLocation>;
+
+		/// How many times an undeliverable message is tried before it's given up
+		type MaxDeliveryAttempts: Get<u32>;
+		/// Blocks to wait before the first retry, doubled after every failed attempt
+		type DeliveryBackoff: Get<Self::BlockNumber>;
+
+		/// Longest status a device may attach to a progress report
+		type MaxStatusLen: Get<u32>;
+
+		/// Most payload schemas a device may accept
+		type MaxDeviceSchemas: Get<u32>;
 
 		type OnReceived: OnReceived<Self>;
+
+		/// Share of the penalty the client gets when a device lets its acceptance window pass
+		type AcceptTimeoutForfeit: Get<Perbill>;
+
+		/// Most orders waiting for a busy device
+		type MaxQueuedOrders: Get<u32>;
+
+		/// Width of the time slices the acceptance deadlines are indexed by
+		type DeadlineSliceLen: Get<Self::Moment>;
+
+		/// Most queued messages sent when a block ends, the rest wait for the next block
+		type MaxBatchedMessages: Get<u32>;
 	}
 
 	// Struct for holding device information.
@@ -96,10 +172,16 @@ pub mod pallet {
 		pub penalty: BalanceOf<T>,
 		pub wcd: MomentOf<T>,
 		pub state: DeviceState,
+		/// Share of the fee the device keeps when its client cancels an accepted order
+		pub cancel_fee: Perbill,
 	}
 
+	/// Storage layout of devices and orders, see `migrations`
+	const STORAGE_VERSION: StorageVersion = StorageVersion::new(4);
+
 	#[pallet::pallet]
 	#[pallet::generate_store(pub(super) trait Store)]
+	#[pallet::storage_version(STORAGE_VERSION)]
 	pub struct Pallet<T>(_);
 
 	/// Device profiles
@@ -112,16 +194,176 @@ pub mod pallet {
 	#[pallet::getter(fn orders)]
 	pub type Orders<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, OrderOf<T>, OptionQuery>;
 
+	/// Orders waiting for their device to finish the current one, oldest first
+	#[pallet::storage]
+	#[pallet::getter(fn order_queue)]
+	pub type OrderQueue<T: Config> = StorageMap<
+		_,
+		Twox64Concat,
+		T::AccountId,
+		BoundedVec<OrderOf<T>, T::MaxQueuedOrders>,
+		OptionQuery,
+	>;
+
+	/// Nonce making ids of local orders unique
+	#[pallet::storage]
+	pub type OrderNonce<T: Config> = StorageValue<_, u64, ValueQuery>;
+
+	/// Messages which couldn't be delivered yet
+	#[pallet::storage]
+	#[pallet::getter(fn outbox)]
+	pub type Outbox<T: Config> =
+		StorageMap<_, Twox64Concat, u64, OutboundMessage<XCMPMessageOf<T>>, OptionQuery>;
+
+	/// Outbox messages to retry at a block
+	#[pallet::storage]
+	pub type OutboxSchedule<T: Config> =
+		StorageMap<_, Twox64Concat, T::BlockNumber, Vec<u64>, ValueQuery>;
+
+	#[pallet::storage]
+	pub type OutboxNonce<T: Config> = StorageValue<_, u64, ValueQuery>;
+
+	/// Protocol version negotiated with each partner chain
+	#[pallet::storage]
+	#[pallet::getter(fn partner_version)]
+	pub type PartnerVersion<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, u32, OptionQuery>;
+
+	/// Client chains told about the devices registered here, managed by governance
+	#[pallet::storage]
+	#[pallet::getter(fn subscriber)]
+	pub type Subscribers<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, (), OptionQuery>;
+
+	/// Messages of the current block, sent in one batch per destination when the block ends
+	#[pallet::storage]
+	pub type PendingBatch<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, Vec<XCMPMessageOf<T>>, ValueQuery>;
+
+	/// Inbound messages too heavy for the block they arrived in, applied later
+	#[pallet::storage]
+	#[pallet::getter(fn overweight)]
+	pub type Overweight<T: Config> =
+		StorageMap<_, Twox64Concat, u64, (MultiLocation, VersionedXCMPMessageOf<T>), OptionQuery>;
+
+	/// Index of the oldest and of the next message of the overweight queue
+	#[pallet::storage]
+	pub type OverweightRange<T: Config> = StorageValue<_, (u64, u64), ValueQuery>;
+
+	/// Execution fees our sovereign account on each partner chain has spent since it was
+	/// last refilled
+	#[pallet::storage]
+	pub type SovereignSpent<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, BalanceOf<T>, ValueQuery>;
+
+	/// Transfers to client chains waiting for spare block weight
+	#[pallet::storage]
+	pub type Transfers<T: Config> =
+		StorageMap<_, Twox64Concat, u64, PendingTransfer<T::AccountId, BalanceOf<T>>, OptionQuery>;
+
+	/// Index of the oldest and of the next transfer of the transfer queue
+	#[pallet::storage]
+	pub type TransferRange<T: Config> = StorageValue<_, (u64, u64), ValueQuery>;
+
+	/// Ends of the acceptance windows of the orders received, by time slice:
+	/// (slice, order) -> (device, due)
+	#[pallet::storage]
+	pub type AcceptDeadlines<T: Config> = StorageDoubleMap<
+		_,
+		Twox64Concat,
+		T::Moment,
+		Twox64Concat,
+		OrderId,
+		(T::AccountId, T::Moment),
+		OptionQuery,
+	>;
+
+	/// Time slice of each deadline in `AcceptDeadlines`, to drop it once its order is accepted
+	/// or settled
+	#[pallet::storage]
+	pub type AcceptDeadlineOf<T: Config> =
+		StorageMap<_, Twox64Concat, OrderId, T::Moment, OptionQuery>;
+
+	/// Earliest time slice of `AcceptDeadlines` which may still hold deadlines
+	#[pallet::storage]
+	pub type NextAcceptDeadline<T: Config> = StorageValue<_, T::Moment, OptionQuery>;
+
+	/// Payload schemas registered by governance
+	#[pallet::storage]
+	#[pallet::getter(fn schemas)]
+	pub type Schemas<T: Config> = StorageMap<_, Twox64Concat, SchemaId, (), OptionQuery>;
+
+	/// Payload schemas each device accepts
+	#[pallet::storage]
+	#[pallet::getter(fn device_schemas)]
+	pub type DeviceSchemas<T: Config> = StorageMap<
+		_,
+		Twox64Concat,
+		T::AccountId,
+		BoundedVec<SchemaId, T::MaxDeviceSchemas>,
+		ValueQuery,
+	>;
+
 	#[pallet::event]
 	#[pallet::generate_deposit(pub(super) fn deposit_event)]
 	pub enum Event<T: Config> {
 		NewDevice(T::AccountId),
-		NewOrder(T::AccountId),
+		NewOrder(T::AccountId, OrderId),
 		Accept(T::AccountId),
 		Reject(T::AccountId),
 		Done(T::AccountId),
+		/// A local order is done with a result. [device, result]
+		ResultReady(T::AccountId, T::OrderResult),
+		/// The device has reported progress of its order. [device, percent]
+		Progress(T::AccountId, Percent),
+		/// A message is malformed for the protocol or can't be put in the XCM version of the
+		/// partner. [message hash]
 		BadVersion(<T as frame_system::Config>::Hash),
 		MessageReceived(Vec<u8>),
+		PartnerVersionChanged(MultiLocation, u32),
+		/// A message is kept in the outbox after a failed delivery. [dest, message]
+		DeliveryFailed(MultiLocation, u64),
+		/// An outbox message is delivered. [dest, message]
+		Delivered(MultiLocation, u64),
+		/// An outbox message is dropped after too many attempts. [dest, message]
+		DeliveryAbandoned(MultiLocation, u64),
+		/// The state of a device has changed. [device, from, to, reason]
+		DeviceStateChanged(T::AccountId, DeviceState, DeviceState, DeviceEvent),
+		/// A payload schema is registered. [schema]
+		SchemaRegistered(SchemaId),
+		/// A payload schema is removed from the registry. [schema]
+		SchemaRemoved(SchemaId),
+		/// A device has declared the payload schemas it accepts. [device]
+		DeviceSchemasSet(T::AccountId),
+		/// The penalty of a late device is sent to the client on its chain.
+		/// [order, client, penalty]
+		PenaltySettled(OrderId, T::AccountId, BalanceOf<T>),
+		/// Sending the penalty failed, the client is paid on this chain. [order]
+		SettlementFailed(OrderId),
+		/// An inbound message is parked until there is weight for it. [sender, index, weight]
+		OverweightEnqueued(MultiLocation, u64, Weight),
+		/// A parked message is applied. [index, weight]
+		OverweightServiced(u64, Weight),
+		/// The client chain has settled an order left past its deadline. [order, device]
+		OrderExpired(OrderId, T::AccountId),
+		/// The device hasn't accepted an order within its acceptance window, the order is
+		/// rejected and a share of the penalty goes to the client. [order, device, forfeit]
+		AcceptTimedOut(OrderId, T::AccountId, BalanceOf<T>),
+		/// An order waits for its busy device. [device, order]
+		OrderQueued(T::AccountId, OrderId),
+		/// The client takes a queued order back. [order, device]
+		OrderWithdrawn(OrderId, T::AccountId),
+		/// A queued order can't be taken by its device anymore, the client is refunded.
+		/// [order, device]
+		OrderDropped(OrderId, T::AccountId),
+		/// The client cancels an order before its deadline, the device keeps the cancellation
+		/// fee. [order, device, fee]
+		Cancelled(OrderId, T::AccountId, BalanceOf<T>),
+		SubscriberAdded(MultiLocation),
+		SubscriberRemoved(MultiLocation),
+		/// Our sovereign account on a partner chain is refilled from the message fund.
+		/// [partner, amount]
+		SovereignToppedUp(MultiLocation, BalanceOf<T>),
 	}
 
 	// Errors inform users that something went wrong.
@@ -137,18 +379,80 @@ pub mod pallet {
 		IllegalState,
 		Overdue,
 		CannotReachDestination,
+		UnsupportedVersion,
+		BadXcmVersion,
+		StatusTooLong,
+		DeviceAbandoned,
+		UnknownSchema,
+		SchemaExists,
+		TooManySchemas,
+		UnsupportedSchema,
+		TransferFailed,
+		UnknownOverweight,
+		WeightOverLimit,
+		QueueFull,
+		DeviceBusy,
+		UnknownSubscriber,
 	}
 
 	#[pallet::hooks]
-	impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {}
+	impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {
+		fn on_initialize(now: T::BlockNumber) -> Weight {
+			// on_finalize can't report what it uses
+			Self::retry_due(now).saturating_add(Self::flush_weight())
+		}
+
+		fn on_idle(_now: T::BlockNumber, remaining_weight: Weight) -> Weight {
+			let mut used = Self::time_out_acceptances(Timestamp::<T>::get(), remaining_weight);
+			used = used.saturating_add(Self::service_transfer_queue(
+				remaining_weight.saturating_sub(used),
+			));
+			used.saturating_add(Self::service_overweight_queue(
+				remaining_weight.saturating_sub(used),
+			))
+		}
+
+		fn on_finalize(_now: T::BlockNumber) {
+			let mut budget = T::MaxBatchedMessages::get() as usize;
+			let mut rest = None;
+			let mut pending = PendingBatch::<T>::drain();
+			while budget > 0 {
+				let (dest, mut msgs) = match pending.next() {
+					Some(batch) => batch,
+					None => break,
+				};
+				if msgs.len() > budget {
+					rest = Some((dest.clone(), msgs.split_off(budget)));
+				}
+				budget -= msgs.len();
+				Self::flush_batch(&dest, msgs);
+			}
+			// the head of the queue for its destination, new messages go after it
+			if let Some((dest, msgs)) = rest {
+				PendingBatch::<T>::insert(dest, msgs);
+			}
+		}
+
+		fn integrity_test() {
+			// client chains call us by position, new calls must not move it
+			let call = Call::<T>::receive_order { msg: Vec::new() };
+			assert_eq!(call.encode()[0], RECEIVE_ORDER_CALL_INDEX);
+			// partners pay for the weight the receiving call declares, a heavier message would
+			// always be parked as overweight
+			for msg in XCMPMessageOf::<T>::samples() {
+				let weight = Self::message_weight(&msg.clone().into());
+				assert!(weight <= RECEIVE_MESSAGE_WEIGHT, "{:?} weighs {}", msg, weight);
+			}
+		}
+	}
 	#[pallet::call]
 	impl<T: Config> Pallet<T> {
 		#[pallet::weight(10_000)]
 		pub fn order(origin: OriginFor<T>, order: OrderBaseOf<T>) -> DispatchResult {
 			let who = ensure_signed(origin)?;
-			let OrderBaseOf::<T> { data, until, fee, device } = order;
-			let order =
-				OrderOf::<T> { fee, data, until, paraid: T::SelfParaId::get(), client: who.into() };
+			let device = order.device.clone();
+			let id = Self::next_order_id(&who, &device, &T::SelfParaId::get());
+			let order = order.convert(id, who, OrderOrigin::Local);
 
 			Self::order_received(order, device)
 		}
@@ -161,12 +465,24 @@ pub mod pallet {
 
 			let now = Timestamp::<T>::get();
 
-			if now < order.until || order.client != who {
+			if order.client != who {
 				return Err(Error::<T>::Prohibited.into());
 			}
 
 			let mut dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
-			// Note. we don't change device state
+			Self::next_state(&dev, DeviceEvent::Cancel)?;
+			if now < order.until {
+				// clients of other chains cancel there
+				if !order.origin.is_local() {
+					return Err(Error::<T>::Prohibited.into());
+				}
+				let accepted = dev.state == DeviceState::Accepted;
+				Self::transition(&device, &mut dev, DeviceEvent::Cancel)?;
+				Self::order_cancelled(&order, device, &mut dev, accepted)?;
+				return Ok(());
+			}
+			Self::charge_response_fee(&who, &order)?;
+			Self::transition(&device, &mut dev, DeviceEvent::Cancel)?;
 			Self::order_reject(Some(&order), now, device, &mut dev, false)
 		}
 		#[pallet::weight(10_000)]
@@ -179,38 +495,78 @@ pub mod pallet {
 
 			let now = Timestamp::<T>::get();
 			if reject {
-				if !matches!(dev.state, DeviceState::Busy | DeviceState::Accepted) {
-					return Err(Error::<T>::IllegalState.into());
+				Self::next_state(&dev, DeviceEvent::reject(onoff))?;
+				if let Some(order) = order.as_ref() {
+					Self::charge_response_fee(&id, order)?;
 				}
-				dev.state = if onoff { DeviceState::Ready } else { DeviceState::Off };
+				Self::transition(&id, &mut dev, DeviceEvent::reject(onoff))?;
 				return Self::order_reject(order.as_ref(), now, id, &mut dev, onoff);
 			}
-			if dev.state != DeviceState::Busy {
-				return Err(Error::<T>::IllegalState.into());
-			}
+			Self::next_state(&dev, DeviceEvent::Accept)?;
 			let order = order.ok_or(Error::<T>::NoOrder)?;
 
 			if now >= order.until {
 				return Err(Error::<T>::Overdue.into());
 			}
+			Self::charge_response_fee(&id, &order)?;
 
-			Self::order_accept(&order, now, id, &mut dev);
-			Ok(())
+			Self::order_accept(&order, now, id, &mut dev)
 		}
 		#[pallet::weight(10_000)]
-		pub fn done(origin: OriginFor<T>, onoff: bool) -> DispatchResult {
+		pub fn done(
+			origin: OriginFor<T>,
+			onoff: bool,
+			result: Option<T::OrderResult>,
+		) -> DispatchResult {
 			let id = ensure_signed(origin)?;
 
 			let mut dev = Device::<T>::get(&id).ok_or(Error::<T>::NoDevice)?;
 
+			// the device has to accept an order before it is done
 			if dev.state != DeviceState::Accepted {
 				return Err(Error::<T>::IllegalState.into());
 			}
+			Self::next_state(&dev, DeviceEvent::done(onoff))?;
 
-			let order = Orders::<T>::take(&id).ok_or(Error::<T>::NoOrder)?;
+			let order = Orders::<T>::get(&id).ok_or(Error::<T>::NoOrder)?;
+			Self::charge_response_fee(&id, &order)?;
+			Orders::<T>::remove(&id);
 			let now = Timestamp::<T>::get();
 
-			Self::order_done(&order, now, id, &mut dev, onoff)
+			Self::order_done(&order, now, id, &mut dev, onoff, result)
+		}
+
+		#[pallet::weight(10_000)]
+		pub fn progress(
+			origin: OriginFor<T>,
+			percent: Percent,
+			status: Option<Vec<u8>>,
+		) -> DispatchResult {
+			let id = ensure_signed(origin)?;
+
+			let dev = Device::<T>::get(&id).ok_or(Error::<T>::NoDevice)?;
+			if dev.state != DeviceState::Accepted {
+				return Err(Error::<T>::IllegalState.into());
+			}
+			if status.as_ref().map_or(false, |s| s.len() > T::MaxStatusLen::get() as usize) {
+				return Err(Error::<T>::StatusTooLong.into());
+			}
+			let order = Orders::<T>::get(&id).ok_or(Error::<T>::NoOrder)?;
+			Self::charge_response_fee(&id, &order)?;
+
+			if let OrderOrigin::Remote(client_chain) = order.origin {
+				let msg: XCMPMessageOf<T> = XCMPMessageOf::<T>::OrderProgress(
+					order.id,
+					order.client,
+					id.clone(),
+					percent,
+					status,
+				);
+				Self::send_message(&client_chain, msg);
+			}
+
+			Self::deposit_event(Event::Progress(id, percent));
+			Ok(())
 		}
 
 		#[pallet::weight(10_000)]
@@ -219,6 +575,7 @@ pub mod pallet {
 			penalty: BalanceOf<T>,
 			wcd: MomentOf<T>,
 			onoff: bool,
+			cancel_fee: Perbill,
 		) -> DispatchResult {
 			let id = ensure_signed(origin)?;
 
@@ -227,15 +584,32 @@ pub mod pallet {
 			}
 			// Despite the order doesn't exist, device can be in Busy,Busy2 state.
 			//
-			Device::<T>::insert(
-				&id,
-				DeviceProfile {
+			let event = if onoff { DeviceEvent::SwitchOn } else { DeviceEvent::SwitchOff };
+			let mut dev = match Device::<T>::get(&id) {
+				Some(mut dev) => {
+					Self::next_state(&dev, event)?;
+					dev.wcd = wcd;
+					dev.penalty = penalty;
+					dev.cancel_fee = cancel_fee;
+					dev
+				},
+				None => DeviceProfile {
 					wcd,
 					penalty,
 					state: if onoff { DeviceState::Ready } else { DeviceState::Off },
+					cancel_fee,
 				},
-			);
-			Self::deposit_event(Event::NewDevice(id));
+			};
+			let registered = Device::<T>::contains_key(&id);
+			Self::transition(&id, &mut dev, event)?;
+			Device::<T>::insert(&id, &dev);
+			Self::deposit_event(Event::NewDevice(id.clone()));
+			Self::announce(if registered {
+				XCMPMessageOf::<T>::DeviceUpdated(id.clone(), penalty, wcd, onoff, cancel_fee)
+			} else {
+				XCMPMessageOf::<T>::DeviceRegistered(id.clone(), penalty, wcd, onoff, cancel_fee)
+			});
+			Self::promote_queued(&id);
 			Ok(())
 		}
 
@@ -243,19 +617,152 @@ pub mod pallet {
 		pub fn set_state(origin: OriginFor<T>, onoff: bool) -> DispatchResult {
 			let id = ensure_signed(origin)?;
 
-			Device::<T>::try_mutate(&id, |d| {
+			let dev = Device::<T>::try_mutate(&id, |d| -> Result<_, DispatchError> {
 				if let Some(ref mut dev) = d {
-					dev.state = match (onoff, &dev.state) {
-						(false, DeviceState::Ready | DeviceState::Off) => DeviceState::Off,
-						//(false, DeviceState::Busy2 | DeviceState::Standby) => DeviceState::Standby,
-						(true, DeviceState::Off) => DeviceState::Ready,
-						_ => return Err(Error::<T>::IllegalState.into()),
-					};
-					Ok(())
+					let event = if onoff { DeviceEvent::SwitchOn } else { DeviceEvent::SwitchOff };
+					Self::transition(&id, dev, event)?;
+					Ok(dev.clone())
 				} else {
 					Err(Error::<T>::NoDevice.into())
 				}
-			})
+			})?;
+			Self::announce(XCMPMessageOf::<T>::DeviceUpdated(
+				id.clone(),
+				dev.penalty,
+				dev.wcd,
+				onoff,
+				dev.cancel_fee,
+			));
+			Self::promote_queued(&id);
+			Ok(())
+		}
+
+		#[pallet::weight(10_000)]
+		pub fn force_xcmp_version(
+			origin: OriginFor<T>,
+			partner: MultiLocation,
+			version: Option<u32>,
+		) -> DispatchResult {
+			ensure_root(origin)?;
+
+			Self::force_version(&partner, version).map_err(Error::<T>::from)?;
+			Ok(())
+		}
+
+		#[pallet::weight(10_000)]
+		pub fn register_schema(origin: OriginFor<T>, schema: SchemaId) -> DispatchResult {
+			ensure_root(origin)?;
+
+			if Schemas::<T>::contains_key(schema) {
+				return Err(Error::<T>::SchemaExists.into());
+			}
+			Schemas::<T>::insert(schema, ());
+			Self::announce_schema_change(schema);
+			Self::deposit_event(Event::SchemaRegistered(schema));
+			Ok(())
+		}
+
+		/// Removes a schema from the registry. Devices keep declaring it but get no new
+		/// orders with it.
+		#[pallet::weight(10_000)]
+		pub fn remove_schema(origin: OriginFor<T>, schema: SchemaId) -> DispatchResult {
+			ensure_root(origin)?;
+
+			if Schemas::<T>::take(schema).is_none() {
+				return Err(Error::<T>::UnknownSchema.into());
+			}
+			Self::announce_schema_change(schema);
+			Self::deposit_event(Event::SchemaRemoved(schema));
+			Ok(())
+		}
+
+		/// Declares the payload schemas the device accepts, replacing the previous ones.
+		#[pallet::weight(10_000)]
+		pub fn set_schemas(origin: OriginFor<T>, schemas: Vec<SchemaId>) -> DispatchResult {
+			let id = ensure_signed(origin)?;
+
+			if !Device::<T>::contains_key(&id) {
+				return Err(Error::<T>::NoDevice.into());
+			}
+			if !schemas.iter().all(|s| Schemas::<T>::contains_key(s)) {
+				return Err(Error::<T>::UnknownSchema.into());
+			}
+			let schemas: BoundedVec<_, _> =
+				schemas.try_into().map_err(|_| Error::<T>::TooManySchemas)?;
+			DeviceSchemas::<T>::insert(&id, schemas);
+			Self::announce(XCMPMessageOf::<T>::DeviceSchemas(
+				id.clone(),
+				Self::accepted_schemas(&id),
+			));
+			Self::deposit_event(Event::DeviceSchemasSet(id));
+			Ok(())
+		}
+
+		/// Receives an encoded protocol message from a client chain.
+		#[pallet::weight(VersionedXCMPMessageOf::<T>::receive_weight_of(msg))]
+		pub fn receive_order(origin: OriginFor<T>, msg: Vec<u8>) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+			Self::receive_message(&who, &msg)
+		}
+
+		/// Applies the parked message `index`, allowing it up to `weight_limit`.
+		#[pallet::weight(weight_limit.saturating_add(10_000))]
+		pub fn service_overweight(
+			origin: OriginFor<T>,
+			index: u64,
+			weight_limit: Weight,
+		) -> DispatchResultWithPostInfo {
+			ensure_root(origin)?;
+			Self::service_parked(index, weight_limit)
+		}
+
+		/// Takes back a local order waiting for its device, with the fee held for it.
+		#[pallet::weight(10_000)]
+		pub fn withdraw(origin: OriginFor<T>, device: T::AccountId, id: OrderId) -> DispatchResult {
+			let who = ensure_signed(origin)?;
+
+			let order = OrderQueue::<T>::try_mutate_exists(
+				&device,
+				|queue| -> Result<OrderOf<T>, DispatchError> {
+					let orders = queue.as_mut().ok_or(Error::<T>::NoOrder)?;
+					let at = orders.iter().position(|o| o.id == id).ok_or(Error::<T>::NoOrder)?;
+					if orders[at].client != who {
+						return Err(Error::<T>::Prohibited.into());
+					}
+					let order = orders.remove(at);
+					if orders.is_empty() {
+						*queue = None;
+					}
+					Ok(order)
+				},
+			)?;
+			T::Currency::unreserve(&who, order.fee);
+
+			Self::deposit_event(Event::OrderWithdrawn(id, device));
+			Ok(())
+		}
+
+		/// Starts telling `chain` about the devices registered here, beginning with the ones
+		/// registered already.
+		#[pallet::weight(10_000)]
+		pub fn add_subscriber(origin: OriginFor<T>, chain: MultiLocation) -> DispatchResult {
+			ensure_root(origin)?;
+
+			Subscribers::<T>::insert(&chain, ());
+			if Self::understands_registry(&chain) {
+				Self::send_registry(&chain);
+			}
+			Self::deposit_event(Event::SubscriberAdded(chain));
+			Ok(())
+		}
+
+		#[pallet::weight(10_000)]
+		pub fn remove_subscriber(origin: OriginFor<T>, chain: MultiLocation) -> DispatchResult {
+			ensure_root(origin)?;
+
+			Subscribers::<T>::remove(&chain);
+			Self::deposit_event(Event::SubscriberRemoved(chain));
+			Ok(())
 		}
 	}
 }
@@ -267,81 +774,242 @@ impl<T: Config> Pallet<T> {
 			return Err(Error::<T>::Overdue.into());
 		}
 
-		if Orders::<T>::contains_key(&device) {
-			return Err(Error::<T>::IllegalState.into());
-		}
-
 		let mut dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
-		if dev.state != DeviceState::Ready {
-			return Err(Error::<T>::IllegalState.into());
+		if Orders::<T>::contains_key(&device) {
+			return Self::queue_order(order, device, &dev, now);
 		}
+		Self::next_state(&dev, DeviceEvent::Order)?;
 
 		if order.until < (now + dev.wcd) {
 			return Err(Error::<T>::BadOrderDetails.into());
 		}
+		Self::ensure_schema(&device, &order.data)?;
 
-		dev.state = T::OnReceived::on_received(&device, &order).ok_or(Error::<T>::IllegalState)?;
-
-		debug_assert!(matches!(dev.state, DeviceState::Busy | DeviceState::Accepted));
+		let accept =
+			match T::OnReceived::on_received(&device, &order).ok_or(Error::<T>::IllegalState)? {
+				DeviceState::Busy => false,
+				DeviceState::Accepted => true,
+				_ => return Err(Error::<T>::IllegalState.into()),
+			};
 
-		if order.paraid == T::SelfParaId::get() {
-			if !T::Currency::can_reserve(&order.client, order.fee) {
-				return Err(Error::<T>::DeviceLowBail.into());
-			}
-			T::Currency::reserve(&device, dev.penalty)?;
+		// the fee of a remote order is held on the chain of its client
+		if order.origin.is_local() && !T::Currency::can_reserve(&order.client, order.fee) {
+			return Err(Error::<T>::DeviceLowBail.into());
+		}
+		T::Currency::reserve(&device, dev.penalty)?;
+		if order.origin.is_local() {
 			T::Currency::reserve(&order.client, order.fee)?;
 		}
 
 		Orders::<T>::insert(&device, &order);
-		Self::deposit_event(Event::NewOrder(device.clone()));
+		Self::deposit_event(Event::NewOrder(device.clone(), order.id));
+		Self::transition(&device, &mut dev, DeviceEvent::Order)?;
 
-		if dev.state == DeviceState::Accepted {
-			Self::order_accept(&order, now, device, &mut dev);
+		if accept {
+			Self::order_accept(&order, now, device, &mut dev)
 		} else {
 			Device::<T>::insert(&device, &dev);
+			Self::schedule_acceptance(now + dev.wcd, device, order.id);
+			Ok(())
+		}
+	}
+
+	/// Keeps an order for a busy device until it's done with the current one. The fee of a
+	/// local client is held meanwhile. Remote orders are refused, they wait in the queue of
+	/// their client chain, which times them out on its own.
+	fn queue_order(
+		order: OrderOf<T>,
+		device: T::AccountId,
+		dev: &DeviceProfile<T>,
+		now: T::Moment,
+	) -> DispatchResult {
+		if !order.origin.is_local() {
+			return Err(Error::<T>::DeviceBusy.into());
+		}
+		if dev.state == DeviceState::Timewait {
+			return Err(Error::<T>::DeviceAbandoned.into());
+		}
+		if order.until < (now + dev.wcd) {
+			return Err(Error::<T>::BadOrderDetails.into());
+		}
+		Self::ensure_schema(&device, &order.data)?;
+		if OrderQueue::<T>::decode_len(&device).unwrap_or(0) >= T::MaxQueuedOrders::get() as usize {
+			return Err(Error::<T>::QueueFull.into());
+		}
+		if !T::Currency::can_reserve(&order.client, order.fee) {
+			return Err(Error::<T>::DeviceLowBail.into());
+		}
+		T::Currency::reserve(&order.client, order.fee)?;
+		let id = order.id;
+		OrderQueue::<T>::try_mutate(&device, |queue| {
+			queue.get_or_insert_with(Default::default).try_push(order)
+		})
+		.map_err(|_| Error::<T>::QueueFull)?;
+
+		Self::deposit_event(Event::OrderQueued(device, id));
+		Ok(())
+	}
+
+	/// Hands the oldest order waiting for `device` to it once the device is ready again.
+	/// Queued orders the device can't take anymore are dropped and their client refunded.
+	fn promote_queued(device: &T::AccountId) {
+		if Orders::<T>::contains_key(device)
+			|| !matches!(Device::<T>::get(device), Some(dev) if dev.state == DeviceState::Ready)
+		{
+			return;
+		}
+		while let Some(order) = Self::pop_queued(device) {
+			// the fee is held again when the order is received
+			T::Currency::unreserve(&order.client, order.fee);
+			let result =
+				with_transaction(|| match Self::order_received(order.clone(), device.clone()) {
+					Ok(()) => TransactionOutcome::Commit(Ok(())),
+					Err(e) => TransactionOutcome::Rollback(Err(e)),
+				});
+			match result {
+				Ok(()) => break,
+				Err(e) => {
+					log::warn!("queued order {:?} dropped: {:?}", order.id, e);
+					Self::deposit_event(Event::OrderDropped(order.id, device.clone()));
+				},
+			}
+		}
+	}
+
+	fn pop_queued(device: &T::AccountId) -> Option<OrderOf<T>> {
+		OrderQueue::<T>::mutate_exists(device, |queue| {
+			let orders = queue.as_mut()?;
+			let order = orders.remove(0);
+			if orders.is_empty() {
+				*queue = None;
+			}
+			Some(order)
+		})
+	}
+
+	/// Sends a change of the device registry to every subscribed client chain. Subscribers
+	/// on version 1 can't be told, they get the whole registry once they upgrade.
+	fn announce(msg: XCMPMessageOf<T>) {
+		for chain in Subscribers::<T>::iter_keys().filter(Self::understands_registry) {
+			Self::send_message(&chain, msg.clone());
+		}
+	}
+
+	/// Whether the protocol version of `chain` has the device registry messages.
+	fn understands_registry(chain: &MultiLocation) -> bool {
+		Self::partner_version_or_default(chain) >= 2
+	}
+
+	/// Sends the devices registered here to `chain`, with the schemas they take orders with.
+	fn send_registry(chain: &MultiLocation) {
+		for (device, dev) in Device::<T>::iter() {
+			// abandoned devices take no orders
+			if dev.state != DeviceState::Timewait {
+				let onoff = dev.state != DeviceState::Off;
+				let msg = XCMPMessageOf::<T>::DeviceRegistered(
+					device.clone(),
+					dev.penalty,
+					dev.wcd,
+					onoff,
+					dev.cancel_fee,
+				);
+				Self::send_message(chain, msg);
+				let schemas = Self::accepted_schemas(&device);
+				Self::send_message(chain, XCMPMessageOf::<T>::DeviceSchemas(device, schemas));
+			}
+		}
+	}
+
+	/// The schemas `device` declares which are still registered, the ones it takes orders with.
+	fn accepted_schemas(device: &T::AccountId) -> Vec<SchemaId> {
+		DeviceSchemas::<T>::get(device)
+			.into_iter()
+			.filter(|schema| Schemas::<T>::contains_key(schema))
+			.collect()
+	}
+
+	/// Tells the subscribers about the devices declaring `schema`, which has been registered
+	/// or removed.
+	fn announce_schema_change(schema: SchemaId) {
+		let devices: Vec<_> = DeviceSchemas::<T>::iter()
+			.filter(|(_, schemas)| schemas.contains(&schema))
+			.map(|(device, _)| device)
+			.collect();
+		for device in devices {
+			let schemas = Self::accepted_schemas(&device);
+			Self::announce(XCMPMessageOf::<T>::DeviceSchemas(device, schemas));
+		}
+	}
+
+	/// Checks the payload follows a registered schema the device accepts.
+	fn ensure_schema(device: &T::AccountId, payload: &T::OrderPayload) -> Result<(), Error<T>> {
+		let schema = payload.schema_id();
+		if !Schemas::<T>::contains_key(schema) || !DeviceSchemas::<T>::get(device).contains(&schema)
+		{
+			return Err(Error::<T>::UnsupportedSchema);
+		}
+		Ok(())
+	}
+
+	/// Returns the state `event` moves `dev` to.
+	fn next_state(dev: &DeviceProfile<T>, event: DeviceEvent) -> Result<DeviceState, Error<T>> {
+		DeviceStateMachine::next(dev.state, event).map_err(|e| match e {
+			TransitionError::NotAllowed { .. } => Error::<T>::IllegalState,
+			TransitionError::Abandoned { .. } => Error::<T>::DeviceAbandoned,
+		})
+	}
+
+	/// Moves `dev` to its next state. The caller stores the profile.
+	fn transition(
+		device: &T::AccountId,
+		dev: &mut DeviceProfile<T>,
+		event: DeviceEvent,
+	) -> Result<(), Error<T>> {
+		let from = dev.state;
+		dev.state = Self::next_state(dev, event)?;
+		if dev.state != from {
+			Self::deposit_event(Event::DeviceStateChanged(device.clone(), from, dev.state, event));
 		}
 		Ok(())
 	}
+
 	fn order_done(
 		order: &OrderOf<T>,
 		now: T::Moment,
 		device: T::AccountId,
 		dev: &mut DeviceProfile<T>,
 		onoff: bool,
+		result: Option<T::OrderResult>,
 	) -> DispatchResult {
-		dev.state = if onoff { DeviceState::Ready } else { DeviceState::Off };
+		Self::transition(&device, dev, DeviceEvent::done(onoff))?;
 
-		let para_id = T::SelfParaId::get();
 		Device::<T>::insert(&device, &*dev);
 
-		if order.paraid == para_id {
-			T::Currency::repatriate_reserved(&order.client, &device, order.fee, Free)?;
+		Self::settle_penalty(order, now, &device, dev.penalty)?;
+		match order.origin {
+			OrderOrigin::Local => {
+				T::Currency::repatriate_reserved(&order.client, &device, order.fee, Free)?;
 
-			if now < order.until {
-				T::Currency::unreserve(&device, dev.penalty);
-			} else {
-				T::Currency::repatriate_reserved(&device, &order.client, dev.penalty, Free)?;
-			}
-		} else {
-			log::info!("send OrderDone message");
-			let msg: XCMPMessageOf<T> =
-				XCMPMessageOf::<T>::OrderDone(order.client.clone(), device.clone(), onoff);
-			let msg: XCMPMessageOf<T> =
-				XCMPMessageOf::<T>::OrderDone(order.client.clone(), device.clone(), onoff);
-			let dest = (Parent, Parachain(order.paraid.into()));
-			let call = msg.encode();
-			let message = Xcm(vec![Instruction::Transact {
-				origin_type: OriginKind::Native,
-				require_weight_at_most: 0,
-				call: call.into(),
-			}]);
-			T::XcmpMessageSender::send_xcm(dest, message)
-				.map_err(|_| Error::<T>::CannotReachDestination)
-				.map(|_| ());
-			log::info!("OrderDone's sent");
-		}
-
-		Self::deposit_event(Event::Done(device));
+				if let Some(result) = result {
+					Self::deposit_event(Event::ResultReady(device.clone(), result));
+				}
+			},
+			OrderOrigin::Remote(ref client_chain) => {
+				log::info!("send OrderDone message");
+				let msg: XCMPMessageOf<T> = XCMPMessageOf::<T>::OrderDone(
+					order.id,
+					order.client.clone(),
+					device.clone(),
+					onoff,
+					result,
+				);
+				Self::send_message(client_chain, msg);
+				log::info!("OrderDone's sent");
+			},
+		}
+
+		Self::deposit_event(Event::Done(device.clone()));
+		Self::promote_queued(&device);
 		Ok(())
 	}
 
@@ -350,27 +1018,19 @@ impl<T: Config> Pallet<T> {
 		_now: T::Moment,
 		device: T::AccountId,
 		dev: &mut DeviceProfile<T>,
-	) {
-		dev.state = DeviceState::Accepted;
+	) -> DispatchResult {
+		Self::transition(&device, dev, DeviceEvent::Accept)?;
 		Device::<T>::insert(&device, &*dev);
-		let para_id = T::SelfParaId::get();
+		Self::unschedule_acceptance(order.id);
 
-		if order.paraid != para_id {
+		if let OrderOrigin::Remote(ref client_chain) = order.origin {
 			let msg: XCMPMessageOf<T> =
-				XCMPMessageOf::<T>::OrderAccept(order.client.clone(), device.clone());
-			let dest = (Parent, Parachain(order.paraid.into()));
-			let call = msg.encode();
-			let message = Xcm(vec![Instruction::Transact {
-				origin_type: OriginKind::Native,
-				require_weight_at_most: 0,
-				call: call.into(),
-			}]);
-			T::XcmpMessageSender::send_xcm(dest, message)
-				.map_err(|_| Error::<T>::CannotReachDestination)
-				.map(|_| ());
+				XCMPMessageOf::<T>::OrderAccept(order.id, order.client.clone(), device.clone());
+			Self::send_message(client_chain, msg);
 		}
 
 		Self::deposit_event(Event::Accept(device));
+		Ok(())
 	}
 
 	fn order_reject(
@@ -381,83 +1041,512 @@ impl<T: Config> Pallet<T> {
 		onoff: bool,
 	) -> DispatchResult {
 		if let Some(order) = order {
-			let para_id = T::SelfParaId::get();
+			Self::settle_penalty(order, now, &device, dev.penalty)?;
+		}
+		Self::release_order(order, device, dev, onoff)
+	}
 
-			if order.paraid == para_id {
-				T::Currency::unreserve(&order.client, order.fee);
-				if now < order.until {
-					T::Currency::unreserve(&device, dev.penalty);
-				} else {
-					T::Currency::repatriate_reserved(&device, &order.client, dev.penalty, Free)?;
-				}
-			} else {
-				log::info!("send OrderReject message");
-				let msg: XCMPMessageOf<T> =
-					XCMPMessageOf::<T>::OrderReject(order.client.clone(), device.clone(), onoff);
-				let dest = (Parent, Parachain(order.paraid.into()));
-				let call = msg.encode();
-				let message = Xcm(vec![Instruction::Transact {
-					origin_type: OriginKind::Native,
-					require_weight_at_most: 0,
-					call: call.into(),
-				}]);
-				T::XcmpMessageSender::send_xcm(dest, message)
-					.map_err(|_| Error::<T>::CannotReachDestination)
-					.map(|_| ());
-				log::info!("OrderReject's sent");
+	/// Gives the client of a rejected order its fee back and frees the device. The penalty is
+	/// settled by the caller.
+	fn release_order(
+		order: Option<&OrderOf<T>>,
+		device: T::AccountId,
+		dev: &mut DeviceProfile<T>,
+		onoff: bool,
+	) -> DispatchResult {
+		if let Some(order) = order {
+			match order.origin {
+				OrderOrigin::Local => {
+					T::Currency::unreserve(&order.client, order.fee);
+				},
+				OrderOrigin::Remote(ref client_chain) => {
+					log::info!("send OrderReject message");
+					let msg: XCMPMessageOf<T> = XCMPMessageOf::<T>::OrderReject(
+						order.id,
+						order.client.clone(),
+						device.clone(),
+						onoff,
+					);
+					Self::send_message(client_chain, msg);
+					log::info!("OrderReject's sent");
+				},
 			}
+			Self::unschedule_acceptance(order.id);
 		}
 		Orders::<T>::remove(&device);
 
 		Device::<T>::insert(&device, &*dev);
-		Self::deposit_event(Event::Reject(device));
+		Self::deposit_event(Event::Reject(device.clone()));
+		Self::promote_queued(&device);
+
+		Ok(())
+	}
+
+	/// Frees a device whose order the client chain `sender` has settled past its deadline.
+	/// The client chain is the judge of the deadline, so the penalty goes to the client even if
+	/// our clock is slightly behind.
+	fn order_expired(
+		sender: &MultiLocation,
+		id: OrderId,
+		client: T::AccountId,
+		device: T::AccountId,
+	) -> DispatchResult {
+		let order = Orders::<T>::get(&device).ok_or(Error::<T>::NoOrder)?;
+		if order.id != id
+			|| order.client != client
+			|| order.origin != OrderOrigin::Remote(sender.clone())
+		{
+			return Err(Error::<T>::Prohibited.into());
+		}
+		let mut dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
+		Self::transition(&device, &mut dev, DeviceEvent::Cancel)?;
+
+		let now = Timestamp::<T>::get().max(order.until);
+		Self::settle_penalty(&order, now, &device, dev.penalty)?;
+		Orders::<T>::remove(&device);
+		Self::unschedule_acceptance(id);
+		Device::<T>::insert(&device, &dev);
+
+		Self::deposit_event(Event::OrderExpired(id, device.clone()));
+		Self::promote_queued(&device);
+		Ok(())
+	}
+
+	/// Frees a device whose order the client chain `sender` has cancelled before its
+	/// deadline. Returns the cancellation fee the device is owed, paid on the client chain.
+	fn cancel_received(
+		sender: &MultiLocation,
+		id: OrderId,
+		client: T::AccountId,
+		device: T::AccountId,
+	) -> Result<BalanceOf<T>, DispatchError> {
+		let order = Orders::<T>::get(&device).ok_or(Error::<T>::NoOrder)?;
+		if order.id != id
+			|| order.client != client
+			|| order.origin != OrderOrigin::Remote(sender.clone())
+		{
+			return Err(Error::<T>::Prohibited.into());
+		}
+		let mut dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
+		let accepted = dev.state == DeviceState::Accepted;
+		Self::transition(&device, &mut dev, DeviceEvent::Cancel)?;
+		Self::order_cancelled(&order, device, &mut dev, accepted)
+	}
+
+	/// Settles an order cancelled before its deadline and returns the cancellation fee the
+	/// device is owed. The device gets its penalty back, and the local client of an accepted
+	/// order pays the device its cancellation fee.
+	fn order_cancelled(
+		order: &OrderOf<T>,
+		device: T::AccountId,
+		dev: &mut DeviceProfile<T>,
+		accepted: bool,
+	) -> Result<BalanceOf<T>, DispatchError> {
+		T::Currency::unreserve(&device, dev.penalty);
+		let fee = if accepted { dev.cancel_fee * order.fee } else { Zero::zero() };
+		if order.origin.is_local() {
+			T::Currency::unreserve(&order.client, order.fee.saturating_sub(fee));
+			if !fee.is_zero() {
+				T::Currency::repatriate_reserved(&order.client, &device, fee, Free)?;
+			}
+		}
+		Orders::<T>::remove(&device);
+		Self::unschedule_acceptance(order.id);
+		Device::<T>::insert(&device, &*dev);
+
+		Self::deposit_event(Event::Cancelled(order.id, device.clone(), fee));
+		Self::promote_queued(&device);
+		Ok(fee)
+	}
+
+	/// Gives the penalty held for `order` back to the device, or to the client if the order is
+	/// overdue.
+	fn settle_penalty(
+		order: &OrderOf<T>,
+		now: T::Moment,
+		device: &T::AccountId,
+		penalty: BalanceOf<T>,
+	) -> DispatchResult {
+		if now < order.until {
+			T::Currency::unreserve(device, penalty);
+			return Ok(());
+		}
+		Self::forfeit_penalty(order, device, penalty)
+	}
 
+	/// Pays `penalty`, held from the device for `order`, to the client.
+	fn forfeit_penalty(
+		order: &OrderOf<T>,
+		device: &T::AccountId,
+		penalty: BalanceOf<T>,
+	) -> DispatchResult {
+		match order.origin {
+			OrderOrigin::Local => {
+				T::Currency::repatriate_reserved(device, &order.client, penalty, Free)?;
+			},
+			OrderOrigin::Remote(ref client_chain) => {
+				Self::settle_later(order.id, device, &order.client, client_chain, penalty);
+			},
+		}
 		Ok(())
 	}
+
+	fn next_order_id<Chain: Encode>(
+		client: &T::AccountId,
+		device: &T::AccountId,
+		chain: &Chain,
+	) -> OrderId {
+		let nonce = OrderNonce::<T>::mutate(|n| {
+			*n = n.wrapping_add(1);
+			*n
+		});
+		order_id(client, device, nonce, chain)
+	}
+
+	/// Queues `msg` for `dest`, it is sent with the rest of the batch when the block ends.
+	fn send_message(dest: &MultiLocation, msg: XCMPMessageOf<T>) {
+		PendingBatch::<T>::append(dest, msg);
+	}
+
+	fn flush_batch(dest: &MultiLocation, mut msgs: Vec<XCMPMessageOf<T>>) {
+		if msgs.len() == 1 {
+			Self::deliver(dest, msgs.remove(0));
+		} else if Self::partner_version_or_default(dest) >= 2 {
+			Self::deliver(dest, XCMPMessageOf::<T>::Batch(msgs));
+		} else {
+			// version 1 partners get the messages one by one
+			for msg in msgs {
+				Self::deliver(dest, msg);
+			}
+		}
+	}
+
+	/// Weight of sending `MaxBatchedMessages` messages when the block ends, each to its own
+	/// destination in the worst case, and of keeping them in the outbox if that fails.
+	fn flush_weight() -> Weight {
+		let count = T::MaxBatchedMessages::get() as Weight;
+		T::DbWeight::get().reads_writes(1 + 4 * count, 6 * count)
+	}
+
+	/// Charges `payer` for the response to `order`, if the order comes from another chain.
+	fn charge_response_fee(payer: &T::AccountId, order: &OrderOf<T>) -> DispatchResult {
+		if order.origin.is_local() {
+			return Ok(());
+		}
+		Self::charge_message_fee(payer)
+	}
 }
 
 impl<T: Config> OnKilledAccount<T::AccountId> for Pallet<T> {
 	fn on_killed_account(who: &T::AccountId) {
-		if let Some(dev) = Device::<T>::get(who) {
-			if dev.state == DeviceState::Off {
+		if let Some(mut dev) = Device::<T>::get(who) {
+			// an idle device goes off right away, a device with an order once it is settled
+			if dev.state == DeviceState::Off
+				|| (Self::transition(who, &mut dev, DeviceEvent::Reaped).is_ok()
+					&& dev.state == DeviceState::Off)
+			{
 				Device::<T>::remove(who);
+				DeviceSchemas::<T>::remove(who);
+			} else {
+				Device::<T>::insert(who, dev);
 			}
+			Self::announce(XCMPMessageOf::<T>::DeviceRemoved(who.clone()));
 		}
 	}
 }
 
-impl<T: Config> XcmpMessageHandler for Pallet<T> {
-	fn handle_xcmp_messages<'a, I: Iterator<Item = (ParaId, RelayBlockNumber, &'a [u8])>>(
-		iter: I,
-		max_weight: Weight,
-	) -> Weight {
-		for (sender, sent_at, data) in iter {
-			let mut data_ref = data;
-			log::warn!("Processing Blob XCM: {:?}", data_ref);
-			match XCMPMessageOf::<T>::decode(&mut data_ref) {
-				Err(e) => {
-					log::error!("{:?}", e);
-					return 0;
-				},
-				Ok(XCMPMessageOf::<T>::NewOrder(client, order)) => {
-					let OrderBaseOf::<T> { data, until, fee, device } = order;
-					let order = OrderOf::<T> { fee, data, until, paraid: sender, client };
-					log::info!("new order received for {:?}", &device);
-					match Self::order_received(order, device) {
-						Err(e) => {
-							log::error!("order_received return {:?}", e);
-						},
-						Ok(_) => {
-							log::info!("order_received succeed");
-						},
+impl<T: Config> Transport for Pallet<T> {
+	type Runtime = T;
+	type Currency = T::Currency;
+	type Payload = T::OrderPayload;
+	type Moment = MomentOf<T>;
+	type OrderResult = T::OrderResult;
+	type Error = Error<T>;
+
+	type XcmpMessageSender = T::XcmpMessageSender;
+	type LocationToAccountId = T::LocationToAccountId;
+	type LocationInverter = T::LocationInverter;
+	type VersionWrapper = T::VersionWrapper;
+	type FeeAsset = T::FeeAsset;
+	type FeeAmount = T::FeeAmount;
+	type XcmInstructionWeight = T::XcmInstructionWeight;
+	type OnXcmFee = T::OnXcmFee;
+	type MessageFund = T::MessageFund;
+	type XcmExecutor = T::XcmExecutor;
+	type AccountIdToMultiLocation = T::AccountIdToMultiLocation;
+	type SettlementAsset = T::SettlementAsset;
+	type MaxDeliveryAttempts = T::MaxDeliveryAttempts;
+	type DeliveryBackoff = T::DeliveryBackoff;
+
+	type PartnerVersion = PartnerVersion<T>;
+	type Outbox = Outbox<T>;
+	type OutboxSchedule = OutboxSchedule<T>;
+	type OutboxNonce = OutboxNonce<T>;
+	type Overweight = Overweight<T>;
+	type OverweightRange = OverweightRange<T>;
+	type SovereignSpent = SovereignSpent<T>;
+	type Transfers = Transfers<T>;
+	type TransferRange = TransferRange<T>;
+
+	// withdraw, buy, transact, refund and deposit
+	const PROGRAM_INSTRUCTIONS: Weight = 5;
+
+	fn receive_call_index() -> (u8, u8) {
+		(T::ClientPalletIndex::get(), RECEIVE_RESPONSE_CALL_INDEX)
+	}
+
+	fn deposit_transport_event(event: TransportEvent<T::AccountId, BalanceOf<T>>) {
+		Self::deposit_event(match event {
+			TransportEvent::BadVersion(msg) => Event::BadVersion(T::Hashing::hash(&msg)),
+			TransportEvent::PartnerVersionChanged(partner, version) => {
+				Event::PartnerVersionChanged(partner, version)
+			},
+			TransportEvent::DeliveryFailed(dest, id) => Event::DeliveryFailed(dest, id),
+			TransportEvent::Delivered(dest, id) => Event::Delivered(dest, id),
+			TransportEvent::DeliveryAbandoned(dest, id) => Event::DeliveryAbandoned(dest, id),
+			TransportEvent::OverweightEnqueued(sender, index, weight) => {
+				Event::OverweightEnqueued(sender, index, weight)
+			},
+			TransportEvent::OverweightServiced(index, weight) => {
+				Event::OverweightServiced(index, weight)
+			},
+			TransportEvent::SovereignToppedUp(dest, amount) => {
+				Event::SovereignToppedUp(dest, amount)
+			},
+			TransportEvent::Settled(id, payee, amount) => Event::PenaltySettled(id, payee, amount),
+			TransportEvent::SettlementFailed(id) => Event::SettlementFailed(id),
+		})
+	}
+
+	/// Weight of applying `msg`, known before it is applied.
+	fn message_weight(msg: &VersionedXCMPMessageOf<T>) -> Weight {
+		// version 1 messages weigh as much as their upgrade
+		let msg = msg.clone().into_latest(|_, _| OrderId::default());
+		T::DbWeight::get().reads_writes(1, 1).saturating_add(Self::latest_weight(&msg))
+	}
+
+	/// Applies a protocol message of any supported version from `sender`.
+	fn receive(sender: &MultiLocation, msg: VersionedXCMPMessageOf<T>) -> DispatchResult {
+		Self::note_version(sender, msg.version());
+		// version 1 clients don't identify orders, we do it for them
+		let msg = msg.into_latest(|client, device| Self::next_order_id(client, device, sender));
+		Self::handle_message(sender, msg)
+	}
+
+	/// Nothing is held on this chain for a remote order, the client chain refunds its client
+	/// when the order is cancelled there.
+	fn on_undelivered(msg: XCMPMessageOf<T>) {
+		log::warn!("undelivered message dropped: {:?}", msg);
+	}
+
+	/// A subscriber which has upgraded from version 1 gets the registry it missed.
+	fn on_version_changed(partner: &MultiLocation, old: Option<u32>, new: u32) {
+		if old.map_or(false, |v| v < 2) && new >= 2 && Subscribers::<T>::contains_key(partner) {
+			Self::send_registry(partner);
+		}
+	}
+}
+
+impl<T: Config> From<TransportError> for Error<T> {
+	fn from(e: TransportError) -> Self {
+		match e {
+			TransportError::CannotReachDestination => Error::<T>::CannotReachDestination,
+			TransportError::UnsupportedVersion => Error::<T>::UnsupportedVersion,
+			TransportError::BadXcmVersion => Error::<T>::BadXcmVersion,
+			TransportError::TransferFailed => Error::<T>::TransferFailed,
+			TransportError::UnknownOverweight => Error::<T>::UnknownOverweight,
+			TransportError::WeightOverLimit => Error::<T>::WeightOverLimit,
+		}
+	}
+}
+
+impl<T: Config> Pallet<T> {
+	fn latest_weight(msg: &XCMPMessageOf<T>) -> Weight {
+		let db = T::DbWeight::get();
+		match msg {
+			XCMPMessageOf::<T>::NewOrder(..) => db.reads_writes(7, 7),
+			XCMPMessageOf::<T>::Version(_) => db.reads_writes(1, 2),
+			XCMPMessageOf::<T>::Ping(_) => db.reads_writes(2, 1),
+			XCMPMessageOf::<T>::OrderExpired(..) => db.reads_writes(3, 4),
+			XCMPMessageOf::<T>::CancelOrder(..) => db.reads_writes(3, 4),
+			XCMPMessageOf::<T>::Batch(msgs) => {
+				msgs.iter().map(Self::latest_weight).fold(0, Weight::saturating_add)
+			},
+			// not meant for a service chain, dropped
+			_ => 0,
+		}
+	}
+
+	/// Time slice of `AcceptDeadlines` a moment falls in.
+	fn deadline_slice(at: T::Moment) -> T::Moment {
+		at / T::DeadlineSliceLen::get().max(One::one())
+	}
+
+	/// Adds the end of the acceptance window of a new order to the index.
+	fn schedule_acceptance(due: T::Moment, device: T::AccountId, id: OrderId) {
+		let slice = Self::deadline_slice(due);
+		AcceptDeadlines::<T>::insert(slice, id, (device, due));
+		AcceptDeadlineOf::<T>::insert(id, slice);
+		NextAcceptDeadline::<T>::mutate(|next| {
+			if next.map_or(true, |next| slice < next) {
+				*next = Some(slice);
+			}
+		});
+	}
+
+	/// Drops the end of the acceptance window of an order from the index.
+	fn unschedule_acceptance(id: OrderId) {
+		if let Some(slice) = AcceptDeadlineOf::<T>::take(id) {
+			AcceptDeadlines::<T>::remove(slice, id);
+		}
+	}
+
+	/// Rejects the orders left unaccepted past their acceptance window at `now`, while they
+	/// fit in `limit`.
+	fn time_out_acceptances(now: T::Moment, limit: Weight) -> Weight {
+		let db = T::DbWeight::get();
+		let mut used = db.reads(1);
+		let first = match NextAcceptDeadline::<T>::get() {
+			Some(next) => next,
+			None => return used,
+		};
+		let last = Self::deadline_slice(now);
+		let mut slice = first;
+		// settlement and the rejection sent to the client chain
+		let cost = db.reads_writes(5, 9);
+		while slice <= last {
+			if used.saturating_add(db.reads(1)) > limit {
+				break;
+			}
+			used = used.saturating_add(db.reads(1));
+			let due: Vec<_> = AcceptDeadlines::<T>::iter_prefix(slice)
+				.filter(|(_, (_, at))| *at <= now)
+				.collect();
+			let mut done = true;
+			for (id, (device, _)) in due {
+				if used.saturating_add(cost) > limit {
+					done = false;
+					break;
+				}
+				used = used.saturating_add(cost);
+				Self::unschedule_acceptance(id);
+				let result = with_transaction(|| match Self::accept_timed_out(id, &device) {
+					Ok(()) => TransactionOutcome::Commit(Ok(())),
+					Err(e) => TransactionOutcome::Rollback(Err(e)),
+				});
+				if let Err(e) = result {
+					log::warn!("cannot time out order {:?}: {:?}", id, e);
+				}
+			}
+			// the current slice may hold deadlines which aren't due yet
+			if !done || slice == last {
+				break;
+			}
+			slice = slice.saturating_add(One::one());
+		}
+		if slice != first {
+			NextAcceptDeadline::<T>::put(slice);
+			used = used.saturating_add(db.writes(1));
+		}
+		used
+	}
+
+	/// Rejects an order the device hasn't accepted in time. The client gets the share of the
+	/// penalty `AcceptTimeoutForfeit` sets, the device keeps the rest. Orders accepted or
+	/// settled meanwhile are skipped.
+	fn accept_timed_out(id: OrderId, device: &T::AccountId) -> DispatchResult {
+		let order = match Orders::<T>::get(device) {
+			Some(order) if order.id == id => order,
+			_ => return Ok(()),
+		};
+		let mut dev = match Device::<T>::get(device) {
+			Some(dev) if dev.state == DeviceState::Busy => dev,
+			_ => return Ok(()),
+		};
+		Self::transition(device, &mut dev, DeviceEvent::Reject)?;
+
+		let forfeit = T::AcceptTimeoutForfeit::get() * dev.penalty;
+		T::Currency::unreserve(device, dev.penalty.saturating_sub(forfeit));
+		if !forfeit.is_zero() {
+			Self::forfeit_penalty(&order, device, forfeit)?;
+		}
+		Self::release_order(Some(&order), device.clone(), &mut dev, true)?;
+
+		Self::deposit_event(Event::AcceptTimedOut(id, device.clone(), forfeit));
+		Ok(())
+	}
+
+	/// Applies a protocol message from `sender`. The messages of a batch are applied one at
+	/// a time, a failing one is rolled back without affecting the others.
+	fn handle_message(sender: &MultiLocation, msg: XCMPMessageOf<T>) -> DispatchResult {
+		match msg {
+			XCMPMessageOf::<T>::NewOrder(id, client, order) => {
+				let device = order.device.clone();
+				let order = order.convert(id, client.clone(), OrderOrigin::Remote(sender.clone()));
+				log::info!("new order received for {:?}", &device);
+				match Self::order_received(order, device.clone()) {
+					Err(e)
+						if e == DispatchError::from(Error::<T>::UnsupportedSchema)
+							|| e == DispatchError::from(Error::<T>::QueueFull)
+							|| e == DispatchError::from(Error::<T>::DeviceBusy) =>
+					{
+						// let the client get its funds back
+						log::warn!("order {:?} can't be taken by the device: {:?}", id, e);
+						let msg: XCMPMessageOf<T> =
+							XCMPMessageOf::<T>::OrderReject(id, client, device, true);
+						Self::send_message(sender, msg);
+						Ok(())
+					},
+					result => result,
+				}
+			},
+			XCMPMessageOf::<T>::Version(version) => {
+				Self::set_partner_version(sender, version);
+				Ok(())
+			},
+			XCMPMessageOf::<T>::Ping(nonce) => {
+				// only chains we announce devices to learn whether we are alive
+				if !Subscribers::<T>::contains_key(sender) {
+					return Err(Error::<T>::UnknownSubscriber.into());
+				}
+				Self::send_message(sender, XCMPMessageOf::<T>::Pong(nonce));
+				Ok(())
+			},
+			XCMPMessageOf::<T>::OrderExpired(id, client, device) => {
+				Self::order_expired(sender, id, client, device)
+			},
+			XCMPMessageOf::<T>::CancelOrder(id, client, device) => {
+				let fee = match Self::cancel_received(sender, id, client.clone(), device.clone()) {
+					Ok(fee) => fee,
+					Err(e) => {
+						log::warn!("cancellation of order {:?} failed: {:?}", id, e);
+						Zero::zero()
+					},
+				};
+				// the client chain holds the cancellation fee until it learns what is owed
+				Self::send_message(
+					sender,
+					XCMPMessageOf::<T>::OrderCancelled(id, client, device, fee),
+				);
+				Ok(())
+			},
+			XCMPMessageOf::<T>::Batch(msgs) => {
+				for msg in msgs {
+					if matches!(msg, XCMPMessageOf::<T>::Batch(_)) {
+						log::warn!("nested batch from {:?} ignored", sender);
+						continue;
 					}
-				},
-				Ok(_) => {
-					log::warn!("unknown XCMP message received");
-					return 0;
-				},
-			};
+					let result = with_transaction(|| match Self::handle_message(sender, msg) {
+						Ok(()) => TransactionOutcome::Commit(Ok(())),
+						Err(e) => TransactionOutcome::Rollback(Err(e)),
+					});
+					if let Err(e) = result {
+						log::error!("batched message from {:?} failed: {:?}", sender, e);
+					}
+				}
+				Ok(())
+			},
+			_ => Err(DispatchError::Other("unknown XCMP message")),
 		}
-		max_weight
 	}
 }
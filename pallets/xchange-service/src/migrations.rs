@@ -0,0 +1,198 @@
+//! Storage migrations of the xchange service pallet.
+//!
+//! Each change of a stored layout has its own step, run in order from the version the chain
+//! is on. The changes were made without bumping the storage version, so no chain has ever
+//! stored an intermediate layout: a chain upgrading runs every step in one go, and the orders
+//! are already gone after the first one. The later steps translate orders anyway, typed with
+//! the current payload, so that each step is complete on its own.
+
+use super::*;
+use frame_support::storage::unhashed;
+use frame_support::traits::{GetStorageVersion, OnRuntimeUpgrade, StorageVersion};
+use pallet_common::migrations::translate_layout;
+use sp_std::marker::PhantomData;
+
+/// Order before it carried an id and the origin of its client.
+#[allow(dead_code)]
+#[derive(Decode)]
+struct OrderV0<Payload, Balance, Moment, AccountId> {
+	until: Moment,
+	data: Payload,
+	fee: Balance,
+	client: AccountId,
+	paraid: ParaId,
+}
+
+/// Order identified by an id, its client chain still given by its `ParaId`.
+#[derive(Encode, Decode)]
+struct OrderV1<Payload, Balance, Moment, AccountId> {
+	id: OrderId,
+	until: Moment,
+	data: Payload,
+	fee: Balance,
+	client: AccountId,
+	paraid: ParaId,
+}
+
+/// Order with an explicit origin, a remote client chain given by its `ParaId`.
+#[derive(Encode, Decode)]
+struct OrderV2<Payload, Balance, Moment, AccountId> {
+	id: OrderId,
+	until: Moment,
+	data: Payload,
+	fee: Balance,
+	client: AccountId,
+	origin: OrderOrigin<ParaId>,
+}
+
+/// Device profile before devices declared a cancellation fee.
+#[derive(Encode, Decode)]
+struct DeviceProfileV3<Balance, Moment> {
+	penalty: Balance,
+	wcd: Moment,
+	state: DeviceState,
+}
+
+type OrderV0Of<T, Payload> =
+	OrderV0<Payload, BalanceOf<T>, MomentOf<T>, <T as frame_system::Config>::AccountId>;
+type OrderV1Of<T> = OrderV1<
+	<T as Config>::OrderPayload,
+	BalanceOf<T>,
+	MomentOf<T>,
+	<T as frame_system::Config>::AccountId,
+>;
+type OrderV2Of<T> = OrderV2<
+	<T as Config>::OrderPayload,
+	BalanceOf<T>,
+	MomentOf<T>,
+	<T as frame_system::Config>::AccountId,
+>;
+type DeviceProfileV3Of<T> = DeviceProfileV3<BalanceOf<T>, MomentOf<T>>;
+
+pub mod v1 {
+	use super::*;
+
+	/// Orders get an id.
+	///
+	/// An order of the old layout has no id the client chain could settle it by, so it is
+	/// dropped, the reserves held for it are given back and the device is ready again. Only
+	/// the fee of a client of this chain is held here. `OldPayload` is the payload type the
+	/// orders were placed with.
+	pub struct MigrateToV1<T, OldPayload>(PhantomData<(T, OldPayload)>);
+	impl<T: Config, OldPayload: Decode> OnRuntimeUpgrade for MigrateToV1<T, OldPayload> {
+		fn on_runtime_upgrade() -> Weight {
+			if Pallet::<T>::on_chain_storage_version() != 0 {
+				return T::DbWeight::get().reads(1);
+			}
+			let mut orders: Weight = 0;
+			Orders::<T>::translate::<OrderV0Of<T, OldPayload>, _>(|device, old| {
+				orders += 1;
+				if old.paraid == T::SelfParaId::get() {
+					T::Currency::unreserve(&old.client, old.fee);
+				}
+				let key = Device::<T>::hashed_key_for(&device);
+				if let Some(dev) = unhashed::get::<DeviceProfileV3Of<T>>(&key) {
+					T::Currency::unreserve(&device, dev.penalty);
+				}
+				log::info!("order of {:?} on {:?} dropped by the migration", old.client, device);
+				None
+			});
+			let devices = translate_layout::<
+				Device<T>,
+				T::AccountId,
+				DeviceProfile<T>,
+				DeviceProfileV3Of<T>,
+				_,
+				_,
+			>(|_, old| {
+				let state = match old.state {
+					DeviceState::Off | DeviceState::Timewait => DeviceState::Off,
+					_ => DeviceState::Ready,
+				};
+				Some(DeviceProfileV3 { state, ..old })
+			});
+			StorageVersion::new(1).put::<Pallet<T>>();
+			T::DbWeight::get().reads_writes(1 + devices + orders * 4, 1 + devices + orders * 3)
+		}
+	}
+}
+
+pub mod v2 {
+	use super::*;
+
+	/// Orders tell whether their client is local or on which chain it lives.
+	pub struct MigrateToV2<T>(PhantomData<T>);
+	impl<T: Config> OnRuntimeUpgrade for MigrateToV2<T> {
+		fn on_runtime_upgrade() -> Weight {
+			if Pallet::<T>::on_chain_storage_version() != 1 {
+				return T::DbWeight::get().reads(1);
+			}
+			let orders = translate_layout::<Orders<T>, T::AccountId, OrderOf<T>, OrderV1Of<T>, _, _>(
+				|_, old| {
+					let origin = if old.paraid == T::SelfParaId::get() {
+						OrderOrigin::Local
+					} else {
+						OrderOrigin::Remote(old.paraid)
+					};
+					let OrderV1 { id, until, data, fee, client, .. } = old;
+					Some(OrderV2 { id, until, data, fee, client, origin })
+				},
+			);
+			StorageVersion::new(2).put::<Pallet<T>>();
+			T::DbWeight::get().reads_writes(1 + orders, 1 + orders)
+		}
+	}
+}
+
+pub mod v3 {
+	use super::*;
+
+	/// Remote clients are addressed by location instead of `ParaId`.
+	pub struct MigrateToV3<T>(PhantomData<T>);
+	impl<T: Config> OnRuntimeUpgrade for MigrateToV3<T> {
+		fn on_runtime_upgrade() -> Weight {
+			if Pallet::<T>::on_chain_storage_version() != 2 {
+				return T::DbWeight::get().reads(1);
+			}
+			let mut orders: Weight = 0;
+			Orders::<T>::translate::<OrderV2Of<T>, _>(|_, old| {
+				orders += 1;
+				let origin = match old.origin {
+					OrderOrigin::Local => OrderOrigin::Local,
+					OrderOrigin::Remote(para_id) => OrderOrigin::Remote(sibling_location(para_id)),
+				};
+				let OrderV2 { id, until, data, fee, client, .. } = old;
+				Some(Order { id, until, data, fee, client, origin })
+			});
+			StorageVersion::new(3).put::<Pallet<T>>();
+			T::DbWeight::get().reads_writes(1 + orders, 1 + orders)
+		}
+	}
+}
+
+pub mod v4 {
+	use super::*;
+
+	/// Devices declare the share of the fee they keep when an accepted order is cancelled.
+	/// Existing devices keep nothing.
+	pub struct MigrateToV4<T>(PhantomData<T>);
+	impl<T: Config> OnRuntimeUpgrade for MigrateToV4<T> {
+		fn on_runtime_upgrade() -> Weight {
+			if Pallet::<T>::on_chain_storage_version() != 3 {
+				return T::DbWeight::get().reads(1);
+			}
+			let mut devices: Weight = 0;
+			Device::<T>::translate::<DeviceProfileV3Of<T>, _>(|_, old| {
+				devices += 1;
+				Some(DeviceProfile {
+					penalty: old.penalty,
+					wcd: old.wcd,
+					state: old.state,
+					cancel_fee: Perbill::zero(),
+				})
+			});
+			StorageVersion::new(4).put::<Pallet<T>>();
+			T::DbWeight::get().reads_writes(1 + devices, 1 + devices)
+		}
+	}
+}
@@ -7,10 +7,12 @@
 use codec::{Decode, Encode};
 use frame_support::weights::Weight;
 use frame_support::{
-	dispatch::DispatchResult,
-	sp_runtime::traits::Hash,
-	sp_runtime::RuntimeDebug,
-	traits::{BalanceStatus::Free, Currency, Get, ReservableCurrency},
+	dispatch::{DispatchError, DispatchResult},
+	sp_runtime::traits::{Convert, Hash, One, Saturating, Zero},
+	sp_runtime::{Perbill, Percent, RuntimeDebug, TransactionOutcome},
+	storage::with_transaction,
+	traits::{Currency, Get, OnUnbalanced, ReservableCurrency},
+	BoundedVec,
 };
 
 use cumulus_primitives_core::ParaId;
@@ -27,17 +29,40 @@ use frame_support::serde::{Deserialize, Serialize};
 use sp_std::convert::{TryFrom, TryInto};
 
 use cumulus_primitives_core::{
-	relay_chain, relay_chain::BlockNumber as RelayBlockNumber, ServiceQuality, XcmpMessageFormat,
-	XcmpMessageHandler,
+	relay_chain, relay_chain::BlockNumber as RelayBlockNumber, ChannelStatus, GetChannelInfo,
+	ServiceQuality,
 };
 
-use xcm::VersionedXcm;
+use frame_support::weights::GetDispatchInfo;
+use xcm::latest::{QueryId, Response};
+use xcm::{VersionedXcm, WrapVersion};
+use xcm_executor::traits::{Convert as XcmConvert, InvertLocation};
 
-type XCMPMessageOf<T> = XCMPMessage<
+use pallet_common::transport::{
+	OutboundMessage, PendingTransfer, Transport, TransportError, TransportEvent,
+};
+
+/// Asks partner chains to report the outcome of our programs.
+pub trait NotifyQuery<Call, BlockNumber> {
+	/// Registers a query `responder` may answer until `timeout`, the answer dispatches
+	/// `notify` with the query id and the response appended.
+	fn new_notify_query(responder: MultiLocation, notify: Call, timeout: BlockNumber) -> QueryId;
+}
+
+pub type XCMPMessageOf<T> = XCMPMessage<
 	<T as frame_system::Config>::AccountId,
 	BalanceOf<T>,
 	<T as Config>::OrderPayload,
 	<T as pallet_timestamp::Config>::Moment,
+	<T as Config>::OrderResult,
+>;
+
+pub type VersionedXCMPMessageOf<T> = VersionedXCMPMessage<
+	<T as frame_system::Config>::AccountId,
+	BalanceOf<T>,
+	<T as Config>::OrderPayload,
+	<T as pallet_timestamp::Config>::Moment,
+	<T as Config>::OrderResult,
 >;
 
 pub type OrderBaseOf<T> = OrderBase<
@@ -52,15 +77,20 @@ pub type OrderOf<T> = Order<
 	BalanceOf<T>,
 	MomentOf<T>,
 	<T as frame_system::Config>::AccountId,
-	ParaId,
+	MultiLocation,
 >;
 
 pub type BalanceOf<T> =
 	<<T as Config>::Currency as Currency<<T as frame_system::Config>::AccountId>>::Balance;
 pub type MomentOf<T> = <T as pallet_timestamp::Config>::Moment;
+pub type NegativeImbalanceOf<T> = <<T as Config>::Currency as Currency<
+	<T as frame_system::Config>::AccountId,
+>>::NegativeImbalance;
 
 type Timestamp<T> = pallet_timestamp::Pallet<T>;
 
+pub mod migrations;
+
 #[frame_support::pallet]
 pub mod pallet {
 	use super::*;
@@ -75,9 +105,82 @@ pub mod pallet {
 
 		type Currency: ReservableCurrency<Self::AccountId>;
 
-		type OrderPayload: Encode + Decode + Clone + Default + Parameter + TypeInfo;
+		type OrderPayload: Encode + Decode + Clone + Default + Parameter + TypeInfo + PayloadSchema;
+		/// What a device hands back to the client with a finished order
+		type OrderResult: Encode + Decode + Clone + Parameter + TypeInfo + MaxEncodedLen;
+		type SelfParaId: Get<ParaId>;
 
 		type XcmpMessageSender: SendXcm;
+		/// Converts partner locations to their sovereign accounts here and back, the calls
+		/// partners send us are dispatched from these accounts
+		type LocationToAccountId: XcmConvert<MultiLocation, Self::AccountId>;
+		/// Finds the location of this chain seen from a partner chain
+		type LocationInverter: InvertLocation;
+		/// Picks the XCM version our programs are sent to a partner in, it asks partners of
+		/// unknown version for theirs meanwhile
+		type VersionWrapper: WrapVersion;
+		/// Registers the queries service chains answer with the outcome of our orders
+		type NotifyQuery: NotifyQuery<Call<Self>, Self::BlockNumber>;
+		/// Origin of the responses to our queries, resolving to the responder
+		type ResponseOrigin: EnsureOrigin<
+			<Self as frame_system::Config>::Origin,
+			Success = MultiLocation,
+		>;
+		/// Blocks a service chain has to report the execution of an order in before the order
+		/// is refunded
+		type DeliveryTimeout: Get<Self::BlockNumber>;
+		/// Index of `pallet_xchange_service` in the runtime of service chains
+		type ServicePalletIndex: Get<u8>;
+
+		/// Asset paying for the execution of our messages on partner chains
+		type FeeAsset: Get<MultiLocation>;
+		/// Amount of the fee asset buying the given weight on partner chains
+		type FeeAmount: Convert<Weight, u128>;
+		/// Weight of one XCM instruction on partner chains
+		type XcmInstructionWeight: Get<Weight>;
+		/// Receives the fees charged to the accounts triggering messages. They should end up
+		/// in `MessageFund`
+		type OnXcmFee: OnUnbalanced<NegativeImbalanceOf<Self>>;
+		/// Account refilling our sovereign accounts on partner chains, which pay for the
+		/// execution of every message we send, including the ones no account triggers
+		type MessageFund: Get<Self::AccountId>;
+
+		/// Executes the transfers settling orders with other chains
+		type XcmExecutor: ExecuteXcm<<Self as frame_system::Config>::Call>;
+		/// Location of a local account, the same on partner chains for their accounts
+		type AccountIdToMultiLocation: Convert<Self::AccountId, MultiLocation>;
+		/// Asset of `Currency` as partner chains see it. Either this chain or the relay chain
+		/// acts as its reserve
+		type SettlementAsset: Get<MultiLocation>;
+
+		/// How many times an undeliverable message is tried before it's given up
+		type MaxDeliveryAttempts: Get<u32>;
+		/// Blocks to wait before the first retry, doubled after every failed attempt
+		type DeliveryBackoff: Get<Self::BlockNumber>;
+
+		/// Longest status a device may attach to a progress report
+		type MaxStatusLen: Get<u32>;
+
+		/// Blocks between two pings of the trusted chains, zero disables them
+		type PingInterval: Get<Self::BlockNumber>;
+		/// Blocks without any message from a trusted chain before it is deemed unreachable
+		type UnreachableAfter: Get<Self::BlockNumber>;
+
+		/// Status of the HRMP channels to sibling chains
+		type ChannelInfo: GetChannelInfo;
+
+		/// Time the service chain has past the acceptance window of a device to report the
+		/// acceptance or the rejection of an order, before the order is rejected here. It also
+		/// has that long to tell what a device is owed for a cancelled order.
+		type AcceptGrace: Get<MomentOf<Self>>;
+
+		/// Most orders waiting for a busy device
+		type MaxQueuedOrders: Get<u32>;
+		/// Most payload schemas a device may accept
+		type MaxDeviceSchemas: Get<u32>;
+
+		/// Width of the time slices the order deadlines are indexed by
+		type DeadlineSliceLen: Get<MomentOf<Self>>;
 	}
 
 	// Struct for holding device information.
@@ -86,12 +189,39 @@ pub mod pallet {
 	pub struct DeviceProfile<T: Config> {
 		pub penalty: BalanceOf<T>,
 		pub wcd: MomentOf<T>,
-		pub para_id: ParaId,
+		/// Where the device lives, its chain or an account on it
+		pub location: MultiLocation,
 		pub state: DeviceState,
+		/// Share of the fee the device keeps when its client cancels an accepted order
+		pub cancel_fee: Perbill,
+	}
+
+	// Latest progress reported by the device working on an order.
+	#[derive(Clone, Encode, Decode, PartialEq, RuntimeDebug, TypeInfo)]
+	#[scale_info(skip_type_params(T))]
+	pub struct ProgressReport<T: Config> {
+		pub percent: Percent,
+		pub status: Option<BoundedVec<u8, T::MaxStatusLen>>,
 	}
 
+	// What happens to an order whose deadline has come.
+	#[derive(Clone, Copy, Encode, Decode, PartialEq, Eq, RuntimeDebug, TypeInfo)]
+	pub enum DeadlineKind {
+		/// The device hasn't accepted the order yet, it's rejected
+		Acceptance,
+		/// The order is still there, it expires
+		Completion,
+		/// The service chain hasn't told what the device is owed for a cancelled order, the
+		/// fee held is given back to the client
+		CancelSettlement,
+	}
+
+	/// Storage layout of devices and orders, see `migrations`
+	const STORAGE_VERSION: StorageVersion = StorageVersion::new(5);
+
 	#[pallet::pallet]
 	#[pallet::generate_store(pub(super) trait Store)]
+	#[pallet::storage_version(STORAGE_VERSION)]
 	pub struct Pallet<T>(_);
 
 	/// Device profiles
@@ -104,15 +234,256 @@ pub mod pallet {
 	#[pallet::getter(fn orders)]
 	pub type Orders<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, OrderOf<T>, OptionQuery>;
 
+	/// Orders waiting for their device to finish the current one, oldest first
+	#[pallet::storage]
+	#[pallet::getter(fn order_queue)]
+	pub type OrderQueue<T: Config> = StorageMap<
+		_,
+		Twox64Concat,
+		T::AccountId,
+		BoundedVec<OrderOf<T>, T::MaxQueuedOrders>,
+		OptionQuery,
+	>;
+
+	/// Latest progress of orders in work
+	#[pallet::storage]
+	#[pallet::getter(fn order_progress)]
+	pub type OrderProgress<T: Config> =
+		StorageMap<_, Twox64Concat, OrderId, ProgressReport<T>, OptionQuery>;
+
+	/// Results of finished orders, kept until their client clears them
+	#[pallet::storage]
+	#[pallet::getter(fn order_result)]
+	pub type OrderResults<T: Config> =
+		StorageMap<_, Twox64Concat, OrderId, (T::AccountId, T::OrderResult), OptionQuery>;
+
+	/// Nonce making order ids unique
+	#[pallet::storage]
+	pub type OrderNonce<T: Config> = StorageValue<_, u64, ValueQuery>;
+
+	/// Messages which couldn't be delivered yet
+	#[pallet::storage]
+	#[pallet::getter(fn outbox)]
+	pub type Outbox<T: Config> =
+		StorageMap<_, Twox64Concat, u64, OutboundMessage<XCMPMessageOf<T>>, OptionQuery>;
+
+	/// Outbox messages to retry at a block
+	#[pallet::storage]
+	pub type OutboxSchedule<T: Config> =
+		StorageMap<_, Twox64Concat, T::BlockNumber, Vec<u64>, ValueQuery>;
+
+	#[pallet::storage]
+	pub type OutboxNonce<T: Config> = StorageValue<_, u64, ValueQuery>;
+
+	/// Protocol version negotiated with each partner chain
+	#[pallet::storage]
+	#[pallet::getter(fn partner_version)]
+	pub type PartnerVersion<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, u32, OptionQuery>;
+
+	/// Service chains whose device announcements are mirrored, managed by governance
+	#[pallet::storage]
+	#[pallet::getter(fn trusted_chain)]
+	pub type TrustedChains<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, (), OptionQuery>;
+
+	/// Inbound messages too heavy for the block they arrived in, applied later
+	#[pallet::storage]
+	#[pallet::getter(fn overweight)]
+	pub type Overweight<T: Config> =
+		StorageMap<_, Twox64Concat, u64, (MultiLocation, VersionedXCMPMessageOf<T>), OptionQuery>;
+
+	/// Index of the oldest and of the next message of the overweight queue
+	#[pallet::storage]
+	pub type OverweightRange<T: Config> = StorageValue<_, (u64, u64), ValueQuery>;
+
+	/// Execution fees our sovereign account on each partner chain has spent since it was
+	/// last refilled
+	#[pallet::storage]
+	pub type SovereignSpent<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, BalanceOf<T>, ValueQuery>;
+
+	/// Transfers to service chains waiting for spare block weight
+	#[pallet::storage]
+	pub type Transfers<T: Config> =
+		StorageMap<_, Twox64Concat, u64, PendingTransfer<T::AccountId, BalanceOf<T>>, OptionQuery>;
+
+	/// Index of the oldest and of the next transfer of the transfer queue
+	#[pallet::storage]
+	pub type TransferRange<T: Config> = StorageValue<_, (u64, u64), ValueQuery>;
+
+	/// Orders whose service chain hasn't reported the execution of the message carrying them
+	#[pallet::storage]
+	#[pallet::getter(fn pending_delivery)]
+	pub type PendingDelivery<T: Config> =
+		StorageMap<_, Twox64Concat, QueryId, (OrderId, T::AccountId), OptionQuery>;
+
+	/// Delivery query of each order in `PendingDelivery`
+	#[pallet::storage]
+	pub type OrderQuery<T: Config> = StorageMap<_, Twox64Concat, OrderId, QueryId, OptionQuery>;
+
+	/// Delivery queries timing out at a block
+	#[pallet::storage]
+	pub type DeliveryTimeouts<T: Config> =
+		StorageMap<_, Twox64Concat, T::BlockNumber, Vec<QueryId>, ValueQuery>;
+
+	/// Block of the latest message from each trusted chain
+	#[pallet::storage]
+	#[pallet::getter(fn last_seen)]
+	pub type LastSeen<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, T::BlockNumber, OptionQuery>;
+
+	/// Blocks the latest answered ping to each trusted chain took
+	#[pallet::storage]
+	#[pallet::getter(fn round_trip)]
+	pub type RoundTrip<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, T::BlockNumber, OptionQuery>;
+
+	/// Nonce and block of the latest unanswered ping to each trusted chain
+	#[pallet::storage]
+	pub type OutstandingPing<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, (u64, T::BlockNumber), OptionQuery>;
+
+	#[pallet::storage]
+	pub type PingNonce<T: Config> = StorageValue<_, u64, ValueQuery>;
+
+	/// Messages waiting for room in the channel to their destination
+	#[pallet::storage]
+	pub type BlockedSends<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, Vec<XCMPMessageOf<T>>, ValueQuery>;
+
+	/// Trusted chains we haven't heard of for too long, they get no new orders
+	#[pallet::storage]
+	#[pallet::getter(fn partner_unreachable)]
+	pub type PartnerUnreachable<T: Config> =
+		StorageMap<_, Blake2_128Concat, MultiLocation, (), OptionQuery>;
+
+	/// Deadlines of the orders placed, by time slice: (slice, (order, kind)) -> (device, due)
+	#[pallet::storage]
+	pub type Deadlines<T: Config> = StorageDoubleMap<
+		_,
+		Twox64Concat,
+		MomentOf<T>,
+		Twox64Concat,
+		(OrderId, DeadlineKind),
+		(T::AccountId, MomentOf<T>),
+		OptionQuery,
+	>;
+
+	/// Time slice of each deadline in `Deadlines`, to drop it once its order is settled
+	#[pallet::storage]
+	pub type OrderDeadlines<T: Config> = StorageDoubleMap<
+		_,
+		Twox64Concat,
+		OrderId,
+		Twox64Concat,
+		DeadlineKind,
+		MomentOf<T>,
+		OptionQuery,
+	>;
+
+	/// Payload schemas each device takes orders with, as its service chain announces them
+	#[pallet::storage]
+	#[pallet::getter(fn device_schemas)]
+	pub type DeviceSchemas<T: Config> = StorageMap<
+		_,
+		Blake2_128Concat,
+		T::AccountId,
+		BoundedVec<SchemaId, T::MaxDeviceSchemas>,
+		ValueQuery,
+	>;
+
+	/// Cancellation fees held until the service chain tells what the device is owed:
+	/// order -> (client, device, location of the device, fee held)
+	#[pallet::storage]
+	pub type PendingCancels<T: Config> = StorageMap<
+		_,
+		Twox64Concat,
+		OrderId,
+		(T::AccountId, T::AccountId, MultiLocation, BalanceOf<T>),
+		OptionQuery,
+	>;
+
+	/// Earliest time slice of `Deadlines` which may still hold deadlines
+	#[pallet::storage]
+	pub type NextDeadline<T: Config> = StorageValue<_, MomentOf<T>, OptionQuery>;
+
 	#[pallet::event]
 	#[pallet::generate_deposit(pub(super) fn deposit_event)]
 	pub enum Event<T: Config> {
 		NewDevice(T::AccountId),
-		NewOrder(T::AccountId, T::AccountId),
+		NewOrder(T::AccountId, T::AccountId, OrderId),
 		Accept(T::AccountId, T::AccountId),
 		Reject(T::AccountId, T::AccountId),
 		Done(T::AccountId, T::AccountId),
+		/// The result of a finished order is stored. [order]
+		ResultReady(OrderId),
+		/// The client has cleared the result of an order. [order]
+		ResultCleared(OrderId),
+		/// The device has reported progress of an order. [order, percent]
+		Progress(OrderId, Percent),
+		/// A message is malformed for the protocol or can't be put in the XCM version of the
+		/// partner. [message hash]
 		BadVersion(<T as frame_system::Config>::Hash),
+		PartnerVersionChanged(MultiLocation, u32),
+		/// A message is kept in the outbox after a failed delivery. [dest, message]
+		DeliveryFailed(MultiLocation, u64),
+		/// An outbox message is delivered. [dest, message]
+		Delivered(MultiLocation, u64),
+		/// An outbox message is dropped after too many attempts. [dest, message]
+		DeliveryAbandoned(MultiLocation, u64),
+		/// A response doesn't refer to the current order of the device. [order, device]
+		StaleMessage(OrderId, T::AccountId),
+		/// The state of a device has changed. [device, from, to, reason]
+		DeviceStateChanged(T::AccountId, DeviceState, DeviceState, DeviceEvent),
+		/// A response about a device comes from a chain other than the device's one.
+		/// [sender, device]
+		UnauthorizedResponse(MultiLocation, T::AccountId),
+		TrustedChainAdded(MultiLocation),
+		TrustedChainRemoved(MultiLocation),
+		/// The fee of an order is sent to the device on its chain. [order, device, fee]
+		FeeSettled(OrderId, T::AccountId, BalanceOf<T>),
+		/// Sending the fee failed, the device is paid on this chain. [order]
+		SettlementFailed(OrderId),
+		/// An inbound message is parked until there is weight for it. [sender, index, weight]
+		OverweightEnqueued(MultiLocation, u64, Weight),
+		/// A parked message is applied. [index, weight]
+		OverweightServiced(u64, Weight),
+		/// The message carrying an order reached the service chain and paid for its execution.
+		/// `Transact` swallows errors of the call it dispatches, so this doesn't tell whether
+		/// the order was taken: a refused order comes back as a rejection, a lost one is caught
+		/// by the acceptance deadline. [order]
+		OrderDelivered(OrderId),
+		/// The message carrying an order failed on the service chain, it is refunded. [order]
+		ExecutionFailed(OrderId),
+		/// The service chain hasn't reported the execution of an order in time, it is
+		/// refunded. [order]
+		DeliveryTimedOut(OrderId),
+		/// Nothing has come from a trusted chain for too long. [chain]
+		PartnerUnreachable(MultiLocation),
+		/// An unreachable trusted chain is heard of again. [chain]
+		PartnerReachable(MultiLocation),
+		/// A message waits for room in the full channel to its destination. [dest]
+		SendQueued(MultiLocation),
+		/// An order is left past its deadline and settled as cancelled. [order, device]
+		OrderExpired(OrderId, T::AccountId),
+		/// Neither the acceptance nor the rejection of an order is reported within the acceptance
+		/// window of the device, the order is rejected. [order, device]
+		AcceptTimedOut(OrderId, T::AccountId),
+		/// An order waits for its busy device. [client, device, order]
+		OrderQueued(T::AccountId, T::AccountId, OrderId),
+		/// The client takes a queued order back. [order, device]
+		OrderWithdrawn(OrderId, T::AccountId),
+		/// The service chain of a device announces a new profile for it. [device]
+		DeviceUpdated(T::AccountId),
+		/// A device leaves the directory. [device]
+		DeviceRemoved(T::AccountId),
+		/// An order cancelled before its deadline is settled, the device gets the cancellation
+		/// fee its service chain says it is owed. [order, device, fee]
+		Cancelled(OrderId, T::AccountId, BalanceOf<T>),
+		/// Our sovereign account on a partner chain is refilled from the message fund.
+		/// [partner, amount]
+		SovereignToppedUp(MultiLocation, BalanceOf<T>),
 	}
 
 	// Errors inform users that something went wrong.
@@ -129,24 +500,75 @@ pub mod pallet {
 		NoOrder,
 		Prohibited,
 		CannotReachDestination,
+		UnsupportedVersion,
+		BadXcmVersion,
+		UnknownOrder,
+		StatusTooLong,
+		NoResult,
+		DeviceAbandoned,
+		UntrustedChain,
+		TransferFailed,
+		UnknownOverweight,
+		WeightOverLimit,
+		UnknownQuery,
+		PartnerUnreachable,
+		NoChannel,
+		QueueFull,
+		UnsupportedSchema,
+		CancelUnsupported,
 	}
 
 	#[pallet::hooks]
-	impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {}
+	impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {
+		fn on_initialize(now: T::BlockNumber) -> Weight {
+			let retries = Self::retry_due(now);
+			let interval = T::PingInterval::get();
+			let pings = if !interval.is_zero() && (now % interval).is_zero() {
+				Self::ping_partners(now)
+			} else {
+				0
+			};
+			retries.saturating_add(Self::time_out_deliveries(now)).saturating_add(pings)
+		}
+
+		fn on_idle(_now: T::BlockNumber, remaining_weight: Weight) -> Weight {
+			let mut used = Self::flush_blocked_sends(remaining_weight);
+			used = used.saturating_add(Self::process_deadlines(
+				Timestamp::<T>::get(),
+				remaining_weight.saturating_sub(used),
+			));
+			used = used.saturating_add(Self::service_transfer_queue(
+				remaining_weight.saturating_sub(used),
+			));
+			used.saturating_add(Self::service_overweight_queue(
+				remaining_weight.saturating_sub(used),
+			))
+		}
+
+		fn integrity_test() {
+			// service chains call us by position, new calls must not move it
+			let call = Call::<T>::receive_response { msg: Vec::new() };
+			assert_eq!(call.encode()[0], RECEIVE_RESPONSE_CALL_INDEX);
+			// partners pay for the weight the receiving call declares, a heavier message would
+			// always be parked as overweight
+			for msg in XCMPMessageOf::<T>::samples() {
+				let weight = Self::message_weight(&msg.clone().into());
+				assert!(weight <= RECEIVE_MESSAGE_WEIGHT, "{:?} weighs {}", msg, weight);
+			}
+		}
+	}
 	#[pallet::call]
 	impl<T: Config> Pallet<T> {
+		/// Checks a trusted chain is alive without waiting for the next round of pings.
 		#[pallet::weight(10_000)]
-		pub fn test(origin: OriginFor<T>) -> DispatchResult {
-			let dest = (Parent, Parachain(200));
-			let call: Vec<u8> = vec![0x00, 0x20].encode();
-			let message = Xcm(vec![Instruction::Transact {
-				origin_type: OriginKind::Native,
-				require_weight_at_most: 0,
-				call: call.into(),
-			}]);
-			T::XcmpMessageSender::send_xcm(dest, message)
-				.map_err(|_| Error::<T>::CannotReachDestination.into())
-				.map(|_| ())
+		pub fn ping(origin: OriginFor<T>, chain: MultiLocation) -> DispatchResult {
+			ensure_root(origin)?;
+
+			if !TrustedChains::<T>::contains_key(&chain) {
+				return Err(Error::<T>::UntrustedChain.into());
+			}
+			Self::send_ping(&chain, frame_system::Pallet::<T>::block_number())?;
+			Ok(())
 		}
 		#[pallet::weight(10_000)]
 		pub fn order(origin: OriginFor<T>, order: OrderBaseOf<T>) -> DispatchResult {
@@ -157,49 +579,63 @@ pub mod pallet {
 			if now >= order.until {
 				return Err(Error::<T>::Overdue.into());
 			}
-			if Orders::<T>::contains_key(&order.device) {
-				return Err(Error::<T>::IllegalState.into());
-			};
 			let mut dev = Device::<T>::get(&order.device).ok_or(Error::<T>::NoDevice)?;
 
-			if dev.state != DeviceState::Ready {
-				return Err(Error::<T>::IllegalState.into());
+			// a busy device takes the order once it's done with the current one
+			let queued = Orders::<T>::contains_key(&order.device);
+			if queued {
+				if dev.state == DeviceState::Timewait {
+					return Err(Error::<T>::DeviceAbandoned.into());
+				}
+				if OrderQueue::<T>::decode_len(&order.device).unwrap_or(0)
+					>= T::MaxQueuedOrders::get() as usize
+				{
+					return Err(Error::<T>::QueueFull.into());
+				}
+			} else {
+				Self::next_state(&dev, DeviceEvent::Order)?;
+			}
+			let chain = chain_location(&dev.location);
+			if !TrustedChains::<T>::contains_key(&chain) {
+				return Err(Error::<T>::UntrustedChain.into());
+			}
+			if PartnerUnreachable::<T>::contains_key(&chain) {
+				return Err(Error::<T>::PartnerUnreachable.into());
+			}
+			if matches!(Self::channel_status(&chain), Some(ChannelStatus::Closed)) {
+				return Err(Error::<T>::NoChannel.into());
 			}
 			if order.until < (now + dev.wcd) {
 				return Err(Error::<T>::BadOrderDetails.into());
 			};
-			if !T::Currency::can_reserve(&who, order.fee) {
+			if !DeviceSchemas::<T>::get(&order.device).contains(&order.data.schema_id()) {
+				return Err(Error::<T>::UnsupportedSchema.into());
+			}
+			if !T::Currency::can_reserve(&who, order.fee.saturating_add(Self::message_fee())) {
 				return Err(Error::<T>::DeviceLowBail.into());
 			}
 
-			T::Currency::reserve(&order.device, dev.penalty)?;
-			T::Currency::reserve(&who, order.fee)?;
 			let device = order.device.clone();
-			// store order
-			let order: OrderBaseOf<T> = {
-				let order: OrderOf<T> = order.convert(who.clone());
-				Orders::<T>::insert(&device, &order);
-				order.convert(device.clone())
-			};
-			let msg: XCMPMessageOf<T> = XCMPMessageOf::<T>::NewOrder(who.clone(), order);
-			let dest = (Parent, Parachain(dev.para_id.into()));
-			let call = msg.encode();
-			let message = Xcm(vec![Instruction::Transact {
-				origin_type: OriginKind::Native,
-				require_weight_at_most: 0,
-				call: call.into(),
-			}]);
-			log::info!("send XCM order message");
-			T::XcmpMessageSender::send_xcm(dest, message)
-				.map_err(|_| Error::<T>::CannotReachDestination)
-				.map(|_| ());
-			log::info!("XCM order message has sent");
-			dev.state = DeviceState::Busy;
-			Device::<T>::insert(&device, &dev);
-
-			Self::deposit_event(Event::NewOrder(who, device.clone()));
+			if queued {
+				// the message fee is charged when the order is sent
+				T::Currency::reserve(&who, order.fee.saturating_add(Self::message_fee()))?;
+				let id = Self::next_order_id(&who, &device);
+				let order: OrderOf<T> = order.convert(id, who.clone(), OrderOrigin::Local);
+				OrderQueue::<T>::try_mutate(&device, |queue| {
+					queue.get_or_insert_with(Default::default).try_push(order)
+				})
+				.map_err(|_| Error::<T>::QueueFull)?;
 
-			Ok(())
+				Self::deposit_event(Event::OrderQueued(who, device, id));
+				return Ok(());
+			}
+
+			Self::charge_message_fee(&who)?;
+			// the penalty of the device is held on its own chain
+			T::Currency::reserve(&who, order.fee)?;
+			let id = Self::next_order_id(&who, &device);
+			let order: OrderOf<T> = order.convert(id, who.clone(), OrderOrigin::Local);
+			Self::place_order(order, &device, &mut dev, now)
 		}
 
 		#[pallet::weight(10_000)]
@@ -210,148 +646,1112 @@ pub mod pallet {
 
 			let now = Timestamp::<T>::get();
 
-			if now < order.until || order.client != who {
+			if order.client != who {
 				return Err(Error::<T>::Prohibited.into());
 			}
 
 			let mut dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
-			// Note. we don't change device state
-			Self::order_reject(who, &order, now, device, &mut dev)
+			Self::next_state(&dev, DeviceEvent::Cancel)?;
+			Self::charge_message_fee(&who)?;
+			let early = now < order.until;
+			// version 1 service chains know nothing of cancellations
+			if early && Self::partner_version_or_default(&chain_location(&dev.location)) < 2 {
+				return Err(Error::<T>::CancelUnsupported.into());
+			}
+			// the device keeps its share of the fee once it has accepted the order, which it
+			// may have done on its chain already. The share stays held until the service chain
+			// tells what the device is owed.
+			let held = if early { dev.cancel_fee * order.fee } else { Zero::zero() };
+			Self::transition(&device, &mut dev, DeviceEvent::Cancel)?;
+			let msg = if early {
+				XCMPMessageOf::<T>::CancelOrder(order.id, who.clone(), device.clone())
+			} else {
+				XCMPMessageOf::<T>::OrderExpired(order.id, who.clone(), device.clone())
+			};
+			// the notice goes ahead of the next queued order
+			Self::send_message(&chain_location(&dev.location), msg);
+			if !held.is_zero() {
+				PendingCancels::<T>::insert(
+					order.id,
+					(who.clone(), device.clone(), dev.location.clone(), held),
+				);
+				Self::schedule_deadline(
+					now + T::AcceptGrace::get(),
+					device.clone(),
+					order.id,
+					DeadlineKind::CancelSettlement,
+				);
+			}
+			// the rest of the fee goes back to the client
+			let rest = OrderOf::<T> { fee: order.fee.saturating_sub(held), ..order.clone() };
+			Self::order_reject(who, &rest, device.clone(), &mut dev)?;
+			if early && held.is_zero() {
+				Self::deposit_event(Event::Cancelled(order.id, device, held));
+			}
+			Ok(())
+		}
+
+		#[pallet::weight(10_000)]
+		pub fn clear_result(origin: OriginFor<T>, id: OrderId) -> DispatchResult {
+			let who = ensure_signed(origin)?;
+
+			let (client, _) = OrderResults::<T>::get(id).ok_or(Error::<T>::NoResult)?;
+			if client != who {
+				return Err(Error::<T>::Prohibited.into());
+			}
+			OrderResults::<T>::remove(id);
+
+			Self::deposit_event(Event::ResultCleared(id));
+			Ok(())
+		}
+
+		/// Drops a device from the directory, e.g. one of a chain which isn't trusted anymore.
+		/// Devices are added by the announcements of their service chains only.
+		#[pallet::weight(10_000)]
+		pub fn forget_device(origin: OriginFor<T>, device: T::AccountId) -> DispatchResult {
+			ensure_root(origin)?;
+
+			let dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
+			Self::remove_device(&device, dev)
+		}
+
+		#[pallet::weight(10_000)]
+		pub fn force_xcmp_version(
+			origin: OriginFor<T>,
+			partner: MultiLocation,
+			version: Option<u32>,
+		) -> DispatchResult {
+			ensure_root(origin)?;
+
+			Self::force_version(&partner, version).map_err(Error::<T>::from)?;
+			Ok(())
+		}
+
+		#[pallet::weight(10_000)]
+		pub fn add_trusted_chain(origin: OriginFor<T>, chain: MultiLocation) -> DispatchResult {
+			ensure_root(origin)?;
+
+			TrustedChains::<T>::insert(&chain, ());
+			Self::deposit_event(Event::TrustedChainAdded(chain));
+			Ok(())
+		}
+
+		/// Stops trusting a service chain. Responses from it are dropped from now on, so
+		/// its orders can only be cancelled once overdue.
+		#[pallet::weight(10_000)]
+		pub fn remove_trusted_chain(origin: OriginFor<T>, chain: MultiLocation) -> DispatchResult {
+			ensure_root(origin)?;
+
+			TrustedChains::<T>::remove(&chain);
+			LastSeen::<T>::remove(&chain);
+			RoundTrip::<T>::remove(&chain);
+			OutstandingPing::<T>::remove(&chain);
+			PartnerUnreachable::<T>::remove(&chain);
+			Self::deposit_event(Event::TrustedChainRemoved(chain));
+			Ok(())
+		}
+
+		/// Receives an encoded protocol message from a service chain.
+		#[pallet::weight(VersionedXCMPMessageOf::<T>::receive_weight_of(msg))]
+		pub fn receive_response(origin: OriginFor<T>, msg: Vec<u8>) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+			Self::receive_message(&who, &msg)
 		}
 
+		/// Applies the parked message `index`, allowing it up to `weight_limit`.
+		#[pallet::weight(weight_limit.saturating_add(10_000))]
+		pub fn service_overweight(
+			origin: OriginFor<T>,
+			index: u64,
+			weight_limit: Weight,
+		) -> DispatchResultWithPostInfo {
+			ensure_root(origin)?;
+			Self::service_parked(index, weight_limit)
+		}
+
+		/// Receives the outcome of a message carrying an order, queried with `pallet_xcm`. Only
+		/// failures of the program itself are reported, not those of the call it dispatches.
 		#[pallet::weight(10_000)]
-		pub fn register(
+		pub fn transact_status(
 			origin: OriginFor<T>,
-			paraid: ParaId,
-			penalty: BalanceOf<T>,
-			wcd: MomentOf<T>,
-			onoff: bool,
+			query_id: QueryId,
+			response: Response,
 		) -> DispatchResult {
-			let id = ensure_signed(origin)?;
+			T::ResponseOrigin::ensure_origin(origin)?;
 
-			if Orders::<T>::contains_key(&id) {
-				return Err(Error::<T>::DeviceExists.into());
+			let (id, device) =
+				PendingDelivery::<T>::take(query_id).ok_or(Error::<T>::UnknownQuery)?;
+			OrderQuery::<T>::remove(id);
+			match response {
+				Response::ExecutionResult(None) => {
+					Self::deposit_event(Event::OrderDelivered(id));
+				},
+				Response::ExecutionResult(Some((index, e))) => {
+					log::warn!("order {:?} failed at instruction {}: {:?}", id, index, e);
+					Self::refund_undelivered(id, &device);
+					Self::deposit_event(Event::ExecutionFailed(id));
+				},
+				response => {
+					log::warn!("unexpected response for order {:?}: {:?}", id, response);
+				},
 			}
-			// Despite the order doesn't exist, device can be in Busy,Busy2 state.
-			//
-			Device::<T>::insert(
-				&id,
-				DeviceProfile {
-					wcd,
-					penalty,
-					state: if onoff { DeviceState::Ready } else { DeviceState::Off },
-					para_id: paraid,
+			Ok(())
+		}
+
+		/// Takes back an order waiting for its device, with the funds held for it.
+		#[pallet::weight(10_000)]
+		pub fn withdraw(origin: OriginFor<T>, device: T::AccountId, id: OrderId) -> DispatchResult {
+			let who = ensure_signed(origin)?;
+
+			let order = OrderQueue::<T>::try_mutate_exists(
+				&device,
+				|queue| -> Result<OrderOf<T>, DispatchError> {
+					let orders = queue.as_mut().ok_or(Error::<T>::NoOrder)?;
+					let at = orders.iter().position(|o| o.id == id).ok_or(Error::<T>::NoOrder)?;
+					if orders[at].client != who {
+						return Err(Error::<T>::Prohibited.into());
+					}
+					let order = orders.remove(at);
+					if orders.is_empty() {
+						*queue = None;
+					}
+					Ok(order)
 				},
-			);
+			)?;
+			T::Currency::unreserve(&who, order.fee.saturating_add(Self::message_fee()));
 
-			Self::deposit_event(Event::NewDevice(id));
+			Self::deposit_event(Event::OrderWithdrawn(id, device));
 			Ok(())
 		}
 	}
 }
 impl<T: Config> Pallet<T> {
-	fn on_accept(who: T::AccountId, device: T::AccountId) -> DispatchResult {
-		Self::deposit_event(Event::Accept(who, device));
+	fn next_order_id(client: &T::AccountId, device: &T::AccountId) -> OrderId {
+		let nonce = OrderNonce::<T>::mutate(|n| {
+			*n = n.wrapping_add(1);
+			*n
+		});
+		order_id(client, device, nonce, &T::SelfParaId::get())
+	}
+
+	/// Mirrors the profile a device has announced on its service chain `sender`.
+	fn on_device_announced(
+		sender: &MultiLocation,
+		device: T::AccountId,
+		penalty: BalanceOf<T>,
+		wcd: MomentOf<T>,
+		onoff: bool,
+		cancel_fee: Perbill,
+	) -> DispatchResult {
+		if !TrustedChains::<T>::contains_key(sender) {
+			return Err(Error::<T>::UntrustedChain.into());
+		}
+		match Device::<T>::get(&device) {
+			// a chain can't take over the device of another one
+			Some(dev) if chain_location(&dev.location) != *sender => {
+				Self::deposit_event(Event::UnauthorizedResponse(sender.clone(), device));
+				return Ok(());
+			},
+			Some(mut dev) => {
+				dev.penalty = penalty;
+				dev.wcd = wcd;
+				dev.cancel_fee = cancel_fee;
+				let event = if onoff { DeviceEvent::SwitchOn } else { DeviceEvent::SwitchOff };
+				// a device with an order in flight keeps the state the order gives it
+				if let Err(e) = Self::transition(&device, &mut dev, event) {
+					log::info!("{:?} of {:?} ignored: {:?}", event, device, e);
+				}
+				Device::<T>::insert(&device, &dev);
+				Self::deposit_event(Event::DeviceUpdated(device.clone()));
+			},
+			None => {
+				let dev = DeviceProfile {
+					penalty,
+					wcd,
+					location: sender.clone(),
+					state: if onoff { DeviceState::Ready } else { DeviceState::Off },
+					cancel_fee,
+				};
+				Device::<T>::insert(&device, dev);
+				Self::deposit_event(Event::NewDevice(device.clone()));
+			},
+		}
+		Self::promote_queued(&device);
+		Ok(())
+	}
+
+	/// Records the payload schemas a device of the service chain `sender` takes orders with.
+	fn on_device_schemas(
+		sender: &MultiLocation,
+		device: T::AccountId,
+		mut schemas: Vec<SchemaId>,
+	) -> DispatchResult {
+		let dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
+		if !TrustedChains::<T>::contains_key(sender) || chain_location(&dev.location) != *sender {
+			Self::deposit_event(Event::UnauthorizedResponse(sender.clone(), device));
+			return Ok(());
+		}
+		schemas.truncate(T::MaxDeviceSchemas::get() as usize);
+		let schemas: BoundedVec<_, _> = schemas.try_into().unwrap_or_default();
+		DeviceSchemas::<T>::insert(&device, schemas);
 		Ok(())
 	}
 
-	fn on_reject(who: T::AccountId, device: T::AccountId, onoff: bool) -> DispatchResult {
-		let order = Orders::<T>::get(&device).ok_or(Error::<T>::NoOrder)?;
+	/// Drops a device its service chain `sender` announces gone.
+	fn on_device_removed(sender: &MultiLocation, device: T::AccountId) -> DispatchResult {
+		let dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
+		if !TrustedChains::<T>::contains_key(sender) || chain_location(&dev.location) != *sender {
+			Self::deposit_event(Event::UnauthorizedResponse(sender.clone(), device));
+			return Ok(());
+		}
+		Self::remove_device(&device, dev)
+	}
 
-		let now = Timestamp::<T>::get();
-		let mut dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
+	/// Drops a device from the directory, refunding the orders queued for it. A device with an
+	/// order in flight is abandoned instead, it takes no new orders.
+	fn remove_device(device: &T::AccountId, mut dev: DeviceProfile<T>) -> DispatchResult {
+		while let Some(order) = Self::pop_queued(device) {
+			T::Currency::unreserve(&order.client, order.fee.saturating_add(Self::message_fee()));
+			Self::deposit_event(Event::Reject(order.client, device.clone()));
+		}
+		if !Orders::<T>::contains_key(device) {
+			Device::<T>::remove(device);
+			DeviceSchemas::<T>::remove(device);
+		} else if dev.state != DeviceState::Timewait {
+			Self::transition(device, &mut dev, DeviceEvent::Reaped)?;
+			Device::<T>::insert(device, &dev);
+		}
+		Self::deposit_event(Event::DeviceRemoved(device.clone()));
+		Ok(())
+	}
 
-		dev.state = if !onoff { DeviceState::Off } else { DeviceState::Ready };
+	/// Sends `order` to the chain of `device` and tracks its deadlines. The funds of the client
+	/// are held by the caller.
+	fn place_order(
+		order: OrderOf<T>,
+		device: &T::AccountId,
+		dev: &mut DeviceProfile<T>,
+		now: MomentOf<T>,
+	) -> DispatchResult {
+		let id = order.id;
+		let who = order.client.clone();
+		let until = order.until;
+		Orders::<T>::insert(device, &order);
+		let msg: XCMPMessageOf<T> =
+			XCMPMessageOf::<T>::NewOrder(id, who.clone(), order.convert(device.clone()));
+		log::info!("send XCM order message");
+		Self::send_message(&chain_location(&dev.location), msg);
+		log::info!("XCM order message has sent");
+		Self::transition(device, dev, DeviceEvent::Order)?;
+		Device::<T>::insert(device, &*dev);
+		Self::schedule_deadline(
+			now + dev.wcd + T::AcceptGrace::get(),
+			device.clone(),
+			id,
+			DeadlineKind::Acceptance,
+		);
+		Self::schedule_deadline(until, device.clone(), id, DeadlineKind::Completion);
 
-		Self::order_reject(who, &order, now, device, &mut dev)
+		Self::deposit_event(Event::NewOrder(who, device.clone(), id));
+		Ok(())
 	}
 
-	fn on_done(who: T::AccountId, device: T::AccountId, onoff: bool) -> DispatchResult {
-		let order = Orders::<T>::get(&device).ok_or(Error::<T>::NoOrder)?;
+	/// Sends the oldest order waiting for `device` once the device is ready again. Queued
+	/// orders which can't be met in time anymore, or whose client can't pay the message fee,
+	/// are dropped and refunded.
+	fn promote_queued(device: &T::AccountId) {
+		if Orders::<T>::contains_key(device) {
+			return;
+		}
+		let mut dev = match Device::<T>::get(device) {
+			Some(dev) if dev.state == DeviceState::Ready => dev,
+			_ => return,
+		};
 		let now = Timestamp::<T>::get();
-		let mut dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
+		while let Some(order) = Self::pop_queued(device) {
+			T::Currency::unreserve(&order.client, order.fee.saturating_add(Self::message_fee()));
+			if order.until < now + dev.wcd {
+				Self::deposit_event(Event::OrderExpired(order.id, device.clone()));
+				continue;
+			}
+			let client = order.client.clone();
+			let placed = with_transaction(|| {
+				let result = Self::charge_message_fee(&client)
+					.and_then(|_| T::Currency::reserve(&client, order.fee))
+					.and_then(|_| Self::place_order(order, device, &mut dev, now));
+				match result {
+					Ok(()) => TransactionOutcome::Commit(Ok(())),
+					Err(e) => TransactionOutcome::Rollback(Err(e)),
+				}
+			});
+			match placed {
+				Ok(()) => break,
+				Err(e) => {
+					log::warn!("queued order for {:?} dropped: {:?}", device, e);
+					Self::deposit_event(Event::Reject(client, device.clone()));
+				},
+			}
+		}
+	}
 
-		T::Currency::repatriate_reserved(&who, &device, order.fee, Free)?;
+	fn pop_queued(device: &T::AccountId) -> Option<OrderOf<T>> {
+		OrderQueue::<T>::mutate_exists(device, |queue| {
+			let orders = queue.as_mut()?;
+			let order = orders.remove(0);
+			if orders.is_empty() {
+				*queue = None;
+			}
+			Some(order)
+		})
+	}
 
-		if now < order.until {
-			T::Currency::unreserve(&device, dev.penalty);
-		} else {
-			T::Currency::repatriate_reserved(&device, &who, dev.penalty, Free)?;
+	/// Checks a response about `device` comes from the trusted chain the device lives on.
+	fn authorize(sender: &MultiLocation, device: &T::AccountId) -> bool {
+		let authorized = TrustedChains::<T>::contains_key(sender)
+			&& Device::<T>::get(device)
+				.map_or(false, |dev| chain_location(&dev.location) == *sender);
+		if !authorized {
+			log::warn!("response about {:?} from unauthorized chain {:?}", device, sender);
+			Self::deposit_event(Event::UnauthorizedResponse(sender.clone(), device.clone()));
 		}
-		Orders::<T>::remove(&device);
+		authorized
+	}
+
+	/// Returns the current order of `device` if it is the one a response refers to.
+	fn message_order(
+		id: OrderId,
+		who: &T::AccountId,
+		device: &T::AccountId,
+	) -> Result<OrderOf<T>, DispatchError> {
+		match Orders::<T>::get(device) {
+			Some(order) if order.id == id && order.client == *who => {
+				// any answer of the service chain proves the order has been delivered
+				Self::note_delivered(id);
+				Ok(order)
+			},
+			_ => {
+				Self::deposit_event(Event::StaleMessage(id, device.clone()));
+				Err(Error::<T>::UnknownOrder.into())
+			},
+		}
+	}
+
+	/// Forgets the delivery query of an order its service chain has answered for.
+	fn note_delivered(id: OrderId) {
+		if let Some(query_id) = OrderQuery::<T>::take(id) {
+			PendingDelivery::<T>::remove(query_id);
+		}
+	}
 
-		dev.state = if !onoff { DeviceState::Off } else { DeviceState::Ready };
+	/// Returns the state `event` moves `dev` to.
+	fn next_state(dev: &DeviceProfile<T>, event: DeviceEvent) -> Result<DeviceState, Error<T>> {
+		DeviceStateMachine::next(dev.state, event).map_err(|e| match e {
+			TransitionError::NotAllowed { .. } => Error::<T>::IllegalState,
+			TransitionError::Abandoned { .. } => Error::<T>::DeviceAbandoned,
+		})
+	}
+
+	/// Moves `dev` to its next state. The caller stores the profile.
+	fn transition(
+		device: &T::AccountId,
+		dev: &mut DeviceProfile<T>,
+		event: DeviceEvent,
+	) -> Result<(), Error<T>> {
+		let from = dev.state;
+		dev.state = Self::next_state(dev, event)?;
+		if dev.state != from {
+			Self::deposit_event(Event::DeviceStateChanged(device.clone(), from, dev.state, event));
+		}
+		Ok(())
+	}
 
+	fn on_accept(id: OrderId, who: T::AccountId, device: T::AccountId) -> DispatchResult {
+		Self::message_order(id, &who, &device)?;
+		let mut dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
+		Self::transition(&device, &mut dev, DeviceEvent::Accept)?;
 		Device::<T>::insert(&device, &dev);
-		Self::deposit_event(Event::Done(who, device));
+		Self::unschedule_deadline(id, DeadlineKind::Acceptance);
+		Self::deposit_event(Event::Accept(who, device));
 		Ok(())
 	}
+
+	fn on_progress(
+		id: OrderId,
+		who: T::AccountId,
+		device: T::AccountId,
+		percent: Percent,
+		status: Option<Vec<u8>>,
+	) -> DispatchResult {
+		Self::message_order(id, &who, &device)?;
+		let status = status
+			.map(BoundedVec::try_from)
+			.transpose()
+			.map_err(|_| Error::<T>::StatusTooLong)?;
+		OrderProgress::<T>::insert(id, ProgressReport { percent, status });
+		Self::deposit_event(Event::Progress(id, percent));
+		Ok(())
+	}
+
+	fn on_reject(
+		id: OrderId,
+		who: T::AccountId,
+		device: T::AccountId,
+		onoff: bool,
+	) -> DispatchResult {
+		let order = Self::message_order(id, &who, &device)?;
+
+		let mut dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
+
+		Self::transition(&device, &mut dev, DeviceEvent::reject(onoff))?;
+
+		Self::order_reject(who, &order, device, &mut dev)
+	}
+
+	fn on_done(
+		id: OrderId,
+		who: T::AccountId,
+		device: T::AccountId,
+		onoff: bool,
+		result: Option<T::OrderResult>,
+	) -> DispatchResult {
+		let order = Self::message_order(id, &who, &device)?;
+		let mut dev = Device::<T>::get(&device).ok_or(Error::<T>::NoDevice)?;
+		let event = DeviceEvent::done(onoff);
+		Self::next_state(&dev, event)?;
+
+		// a late device pays its penalty on its own chain
+		Self::settle_later(id, &who, &device, &dev.location, order.fee);
+		Self::remove_order(&device, &order);
+
+		Self::transition(&device, &mut dev, event)?;
+
+		Device::<T>::insert(&device, &dev);
+		if let Some(result) = result {
+			OrderResults::<T>::insert(id, (who.clone(), result));
+			Self::deposit_event(Event::ResultReady(id));
+		}
+		Self::deposit_event(Event::Done(who, device.clone()));
+		Self::promote_queued(&device);
+		Ok(())
+	}
+
+	fn remove_order(device: &T::AccountId, order: &OrderOf<T>) {
+		Orders::<T>::remove(device);
+		OrderProgress::<T>::remove(order.id);
+		Self::unschedule_deadline(order.id, DeadlineKind::Acceptance);
+		Self::unschedule_deadline(order.id, DeadlineKind::Completion);
+	}
+
 	fn order_reject(
 		who: T::AccountId,
 		order: &OrderOf<T>,
-		now: T::Moment,
 		device: T::AccountId,
 		dev: &mut DeviceProfile<T>,
 	) -> DispatchResult {
 		T::Currency::unreserve(&who, order.fee);
 
-		if now < order.until {
-			T::Currency::unreserve(&device, dev.penalty);
-		} else {
-			T::Currency::repatriate_reserved(&device, &order.client, dev.penalty, Free)?;
+		Self::remove_order(&device, order);
+		Device::<T>::insert(&device, &*dev);
+
+		Self::deposit_event(Event::Reject(who, device.clone()));
+		Self::promote_queued(&device);
+		Ok(())
+	}
+
+	/// Sends `msg` to `dest`, queued behind the earlier ones while the channel is full.
+	fn send_message(dest: &MultiLocation, msg: XCMPMessageOf<T>) {
+		let full = matches!(Self::channel_status(dest), Some(ChannelStatus::Full));
+		if full || BlockedSends::<T>::contains_key(dest) {
+			BlockedSends::<T>::append(dest, msg);
+			Self::deposit_event(Event::SendQueued(dest.clone()));
+			return;
 		}
+		Self::deliver(dest, msg)
+	}
 
-		Orders::<T>::remove(&device);
-		Device::<T>::insert(&device, &*dev);
+	/// Status of the HRMP channel to `dest`, if it is reached over one.
+	fn channel_status(dest: &MultiLocation) -> Option<ChannelStatus> {
+		match (dest.parents, dest.first_interior()) {
+			(1, Some(Parachain(id))) => Some(T::ChannelInfo::get_channel_status((*id).into())),
+			_ => None,
+		}
+	}
+
+	/// Sends the queued messages of the channels with room again, while they fit in `limit`.
+	fn flush_blocked_sends(limit: Weight) -> Weight {
+		let db = T::DbWeight::get();
+		let mut used: Weight = 0;
+		let dests: Vec<_> = BlockedSends::<T>::iter_keys().collect();
+		for dest in dests {
+			let cost = db.reads_writes(2, 1);
+			if used.saturating_add(cost) > limit {
+				break;
+			}
+			used = used.saturating_add(cost);
+			if matches!(Self::channel_status(&dest), Some(ChannelStatus::Full)) {
+				continue;
+			}
+			let mut msgs = BlockedSends::<T>::take(&dest).into_iter();
+			while let Some(msg) = msgs.next() {
+				let cost = db.reads_writes(3, 3);
+				if used.saturating_add(cost) > limit
+					|| matches!(Self::channel_status(&dest), Some(ChannelStatus::Full))
+				{
+					// the rest keeps its place for the next block
+					let rest: Vec<_> = sp_std::iter::once(msg).chain(msgs).collect();
+					BlockedSends::<T>::insert(&dest, rest);
+					break;
+				}
+				used = used.saturating_add(cost);
+				Self::deliver(&dest, msg);
+			}
+		}
+		used
+	}
 
-		Self::deposit_event(Event::Reject(who, device));
+	/// Settles the cancellation fee held for an order once its service chain, `sender`, has
+	/// told what the device is owed. The client gets back what the device isn't owed.
+	fn on_cancelled(
+		sender: Option<&MultiLocation>,
+		id: OrderId,
+		client: T::AccountId,
+		device: T::AccountId,
+		fee: BalanceOf<T>,
+	) {
+		let (location, held) = match PendingCancels::<T>::get(id) {
+			Some((who, dev, location, held))
+				if who == client
+					&& dev == device
+					&& sender.map_or(true, |s| chain_location(&location) == *s) =>
+			{
+				(location, held)
+			},
+			_ => {
+				if sender.is_some() {
+					Self::deposit_event(Event::StaleMessage(id, device));
+				}
+				return;
+			},
+		};
+		PendingCancels::<T>::remove(id);
+		Self::unschedule_deadline(id, DeadlineKind::CancelSettlement);
+		let fee = fee.min(held);
+		T::Currency::unreserve(&client, held.saturating_sub(fee));
+		if !fee.is_zero() {
+			Self::settle_later(id, &client, &device, &location, fee);
+		}
+		Self::deposit_event(Event::Cancelled(id, device, fee));
+	}
+
+	/// Refunds an order the service chain has never heard of. An order the device has
+	/// accepted meanwhile is left alone, the service chain is working on it.
+	fn refund_undelivered(id: OrderId, device: &T::AccountId) {
+		match (Orders::<T>::get(device), Device::<T>::get(device)) {
+			(Some(order), Some(mut dev)) if order.id == id && dev.state == DeviceState::Busy => {
+				// in case the order got there after all
+				Self::send_message(
+					&chain_location(&dev.location),
+					XCMPMessageOf::<T>::CancelOrder(id, order.client.clone(), device.clone()),
+				);
+				T::Currency::unreserve(&order.client, order.fee);
+				Self::remove_order(device, &order);
+				if Self::transition(device, &mut dev, DeviceEvent::Reject).is_err() {
+					log::warn!("unexpected device state on undelivered order");
+				}
+				Device::<T>::insert(device, &dev);
+				Self::deposit_event(Event::Reject(order.client, device.clone()));
+				Self::promote_queued(device);
+			},
+			_ => {},
+		}
+	}
+
+	/// Pings every trusted chain, flagging the ones we haven't heard of for too long.
+	fn ping_partners(now: T::BlockNumber) -> Weight {
+		let chains: Vec<_> = TrustedChains::<T>::iter_keys().collect();
+		let count = chains.len() as Weight;
+		for chain in chains {
+			// version 1 partners can't answer pings, they are never deemed unreachable
+			if Self::partner_version_or_default(&chain) < 2 {
+				continue;
+			}
+			match LastSeen::<T>::get(&chain) {
+				// the clock starts with the first ping
+				None => LastSeen::<T>::insert(&chain, now),
+				Some(seen)
+					if now.saturating_sub(seen) >= T::UnreachableAfter::get()
+						&& !PartnerUnreachable::<T>::contains_key(&chain) =>
+				{
+					log::warn!("nothing from {:?} since block {:?}", chain, seen);
+					PartnerUnreachable::<T>::insert(&chain, ());
+					Self::deposit_event(Event::PartnerUnreachable(chain.clone()));
+				},
+				Some(_) => {},
+			}
+			if let Err(e) = Self::send_ping(&chain, now) {
+				log::warn!("ping to {:?} failed: {:?}", chain, e);
+			}
+		}
+		T::DbWeight::get().reads_writes(1 + 4 * count, 4 * count)
+	}
+
+	fn send_ping(chain: &MultiLocation, now: T::BlockNumber) -> Result<(), Error<T>> {
+		let nonce = PingNonce::<T>::mutate(|n| {
+			*n = n.wrapping_add(1);
+			*n
+		});
+		Self::try_send(chain, XCMPMessageOf::<T>::Ping(nonce))?;
+		OutstandingPing::<T>::insert(chain, (nonce, now));
 		Ok(())
 	}
+
+	/// Notes that a trusted chain is alive.
+	fn note_seen(sender: &MultiLocation) {
+		if !TrustedChains::<T>::contains_key(sender) {
+			return;
+		}
+		LastSeen::<T>::insert(sender, frame_system::Pallet::<T>::block_number());
+		if PartnerUnreachable::<T>::take(sender).is_some() {
+			Self::deposit_event(Event::PartnerReachable(sender.clone()));
+		}
+	}
+
+	fn on_pong(sender: &MultiLocation, nonce: u64) {
+		match OutstandingPing::<T>::get(sender) {
+			Some((expected, sent)) if expected == nonce => {
+				OutstandingPing::<T>::remove(sender);
+				let now = frame_system::Pallet::<T>::block_number();
+				RoundTrip::<T>::insert(sender, now.saturating_sub(sent));
+			},
+			_ => log::info!("stale pong {} from {:?}", nonce, sender),
+		}
+	}
+
+	/// Refunds the orders whose execution hasn't been reported before `now`.
+	fn time_out_deliveries(now: T::BlockNumber) -> Weight {
+		let due = DeliveryTimeouts::<T>::take(now);
+		let count = due.len() as Weight;
+		for query_id in due {
+			// answered queries are gone already
+			if let Some((id, device)) = PendingDelivery::<T>::take(query_id) {
+				OrderQuery::<T>::remove(id);
+				Self::refund_undelivered(id, &device);
+				Self::deposit_event(Event::DeliveryTimedOut(id));
+			}
+		}
+		T::DbWeight::get().reads_writes(1 + 6 * count, 1 + 7 * count)
+	}
+
+	/// Time slice of `Deadlines` a moment falls in.
+	fn deadline_slice(at: MomentOf<T>) -> MomentOf<T> {
+		at / T::DeadlineSliceLen::get().max(One::one())
+	}
+
+	/// Adds a deadline of a new order to the index.
+	fn schedule_deadline(due: MomentOf<T>, device: T::AccountId, id: OrderId, kind: DeadlineKind) {
+		let slice = Self::deadline_slice(due);
+		Deadlines::<T>::insert(slice, (id, kind), (device, due));
+		OrderDeadlines::<T>::insert(id, kind, slice);
+		NextDeadline::<T>::mutate(|next| {
+			if next.map_or(true, |next| slice < next) {
+				*next = Some(slice);
+			}
+		});
+	}
+
+	/// Drops a deadline of an order from the index.
+	fn unschedule_deadline(id: OrderId, kind: DeadlineKind) {
+		if let Some(slice) = OrderDeadlines::<T>::take(id, kind) {
+			Deadlines::<T>::remove(slice, (id, kind));
+		}
+	}
+
+	/// Settles the orders whose deadline has come at `now`, while they fit in `limit`.
+	fn process_deadlines(now: MomentOf<T>, limit: Weight) -> Weight {
+		let db = T::DbWeight::get();
+		let mut used = db.reads(1);
+		let first = match NextDeadline::<T>::get() {
+			Some(next) => next,
+			None => return used,
+		};
+		let last = Self::deadline_slice(now);
+		let mut slice = first;
+		// settlement and the notice sent to the service chain
+		let cost = db.reads_writes(6, 10);
+		while slice <= last {
+			if used.saturating_add(db.reads(1)) > limit {
+				break;
+			}
+			used = used.saturating_add(db.reads(1));
+			let due: Vec<_> =
+				Deadlines::<T>::iter_prefix(slice).filter(|(_, (_, at))| *at <= now).collect();
+			let mut done = true;
+			for ((id, kind), (device, _)) in due {
+				if used.saturating_add(cost) > limit {
+					done = false;
+					break;
+				}
+				used = used.saturating_add(cost);
+				Self::unschedule_deadline(id, kind);
+				match kind {
+					DeadlineKind::Acceptance => Self::accept_timed_out(id, &device),
+					DeadlineKind::Completion => Self::expire_order(id, &device),
+					DeadlineKind::CancelSettlement => Self::cancel_timed_out(id, device),
+				}
+			}
+			// the current slice may hold deadlines which aren't due yet
+			if !done || slice == last {
+				break;
+			}
+			slice = slice.saturating_add(One::one());
+		}
+		if slice != first {
+			NextDeadline::<T>::put(slice);
+			used = used.saturating_add(db.writes(1));
+		}
+		used
+	}
+
+	/// Settles an order left past its deadline as `cancel` does and tells the service chain.
+	/// Orders settled some other way meanwhile are skipped.
+	fn expire_order(id: OrderId, device: &T::AccountId) {
+		let order = match Orders::<T>::get(device) {
+			Some(order) if order.id == id => order,
+			_ => return,
+		};
+		let mut dev = match Device::<T>::get(device) {
+			Some(dev) => dev,
+			None => return,
+		};
+		if let Err(e) = Self::transition(device, &mut dev, DeviceEvent::Cancel) {
+			log::warn!("cannot expire order {}: {:?}", id, e);
+			return;
+		}
+		let client = order.client.clone();
+		// the notice goes ahead of the next queued order
+		Self::send_message(
+			&chain_location(&dev.location),
+			XCMPMessageOf::<T>::OrderExpired(id, client.clone(), device.clone()),
+		);
+		let _ = Self::order_reject(client, &order, device.clone(), &mut dev);
+		Self::deposit_event(Event::OrderExpired(id, device.clone()));
+	}
+
+	/// Gives the client back the fee held for a cancelled order the service chain has kept
+	/// silent about. The device is owed nothing.
+	fn cancel_timed_out(id: OrderId, device: T::AccountId) {
+		if let Some((client, ..)) = PendingCancels::<T>::get(id) {
+			Self::on_cancelled(None, id, client, device, Zero::zero());
+		}
+	}
+
+	/// Rejects an order the service chain has kept silent about past the acceptance window of
+	/// the device. The penalty is settled by the service chain when it times the order out.
+	fn accept_timed_out(id: OrderId, device: &T::AccountId) {
+		let order = match Orders::<T>::get(device) {
+			Some(order) if order.id == id => order,
+			_ => return,
+		};
+		let mut dev = match Device::<T>::get(device) {
+			Some(dev) if dev.state == DeviceState::Busy => dev,
+			_ => return,
+		};
+		if let Err(e) = Self::transition(device, &mut dev, DeviceEvent::Reject) {
+			log::warn!("cannot time out order {}: {:?}", id, e);
+			return;
+		}
+		// the service chain drops the order too, it may still be holding it
+		Self::send_message(
+			&chain_location(&dev.location),
+			XCMPMessageOf::<T>::CancelOrder(id, order.client.clone(), device.clone()),
+		);
+		let _ = Self::order_reject(order.client.clone(), &order, device.clone(), &mut dev);
+		Self::deposit_event(Event::AcceptTimedOut(id, device.clone()));
+	}
 }
 impl<T: Config> OnKilledAccount<T::AccountId> for Pallet<T> {
 	/// The account with the given id was reaped.
 	fn on_killed_account(who: &T::AccountId) {
 		//Timewait
 		if let Some(mut dev) = Device::<T>::get(who) {
-			if dev.state == DeviceState::Off {
+			// an idle device goes off right away, a device with an order once it is settled
+			if dev.state == DeviceState::Off
+				|| (Self::transition(who, &mut dev, DeviceEvent::Reaped).is_ok()
+					&& dev.state == DeviceState::Off)
+			{
 				Device::<T>::remove(who);
+				DeviceSchemas::<T>::remove(who);
 			} else {
-				dev.state = DeviceState::Timewait;
 				Device::<T>::insert(who, dev);
 			}
 		}
 	}
 }
 
-impl<T: Config> XcmpMessageHandler for Pallet<T> {
-	fn handle_xcmp_messages<'a, I: Iterator<Item = (ParaId, RelayBlockNumber, &'a [u8])>>(
-		iter: I,
-		max_weight: Weight,
-	) -> Weight {
-		for (sender, sent_at, data) in iter {
-			let mut data_ref = data;
-			match XCMPMessageOf::<T>::decode(&mut data_ref) {
-				Err(e) => {
-					log::error!("{:?}", e);
-					return 0;
-				},
-				Ok(XCMPMessageOf::<T>::OrderAccept(client, devid)) => {
-					Self::on_accept(client, devid);
-					log::info!("OrderAccept");
-				},
-				Ok(XCMPMessageOf::<T>::OrderReject(client, devid, onoff)) => {
-					Self::on_reject(client, devid, onoff);
-					log::info!("OrderReject");
-				},
-				Ok(XCMPMessageOf::<T>::OrderDone(cliend, devid, onoff)) => {
-					Self::on_done(cliend, devid, onoff);
-					log::info!("OrderDone");
-				},
-				Ok(_) => {
-					log::warn!("unknown XCM message received");
-				},
-			};
+impl<T: Config> Transport for Pallet<T> {
+	type Runtime = T;
+	type Currency = T::Currency;
+	type Payload = T::OrderPayload;
+	type Moment = MomentOf<T>;
+	type OrderResult = T::OrderResult;
+	type Error = Error<T>;
+
+	type XcmpMessageSender = T::XcmpMessageSender;
+	type LocationToAccountId = T::LocationToAccountId;
+	type LocationInverter = T::LocationInverter;
+	type VersionWrapper = T::VersionWrapper;
+	type FeeAsset = T::FeeAsset;
+	type FeeAmount = T::FeeAmount;
+	type XcmInstructionWeight = T::XcmInstructionWeight;
+	type OnXcmFee = T::OnXcmFee;
+	type MessageFund = T::MessageFund;
+	type XcmExecutor = T::XcmExecutor;
+	type AccountIdToMultiLocation = T::AccountIdToMultiLocation;
+	type SettlementAsset = T::SettlementAsset;
+	type MaxDeliveryAttempts = T::MaxDeliveryAttempts;
+	type DeliveryBackoff = T::DeliveryBackoff;
+
+	type PartnerVersion = PartnerVersion<T>;
+	type Outbox = Outbox<T>;
+	type OutboxSchedule = OutboxSchedule<T>;
+	type OutboxNonce = OutboxNonce<T>;
+	type Overweight = Overweight<T>;
+	type OverweightRange = OverweightRange<T>;
+	type SovereignSpent = SovereignSpent<T>;
+	type Transfers = Transfers<T>;
+	type TransferRange = TransferRange<T>;
+
+	// withdraw, buy, transact, refund and deposit, with the appendix reporting the outcome
+	const PROGRAM_INSTRUCTIONS: Weight = 7;
+
+	fn receive_call_index() -> (u8, u8) {
+		(T::ServicePalletIndex::get(), RECEIVE_ORDER_CALL_INDEX)
+	}
+
+	fn deposit_transport_event(event: TransportEvent<T::AccountId, BalanceOf<T>>) {
+		Self::deposit_event(match event {
+			TransportEvent::BadVersion(msg) => Event::BadVersion(T::Hashing::hash(&msg)),
+			TransportEvent::PartnerVersionChanged(partner, version) => {
+				Event::PartnerVersionChanged(partner, version)
+			},
+			TransportEvent::DeliveryFailed(dest, id) => Event::DeliveryFailed(dest, id),
+			TransportEvent::Delivered(dest, id) => Event::Delivered(dest, id),
+			TransportEvent::DeliveryAbandoned(dest, id) => Event::DeliveryAbandoned(dest, id),
+			TransportEvent::OverweightEnqueued(sender, index, weight) => {
+				Event::OverweightEnqueued(sender, index, weight)
+			},
+			TransportEvent::OverweightServiced(index, weight) => {
+				Event::OverweightServiced(index, weight)
+			},
+			TransportEvent::SovereignToppedUp(dest, amount) => {
+				Event::SovereignToppedUp(dest, amount)
+			},
+			TransportEvent::Settled(id, payee, amount) => Event::FeeSettled(id, payee, amount),
+			TransportEvent::SettlementFailed(id) => Event::SettlementFailed(id),
+		})
+	}
+
+	/// Weight of applying `msg`, known before it is applied.
+	fn message_weight(msg: &VersionedXCMPMessageOf<T>) -> Weight {
+		// version 1 messages weigh as much as their upgrade
+		let msg = msg.clone().into_latest(|_, _| OrderId::default());
+		T::DbWeight::get().reads_writes(3, 3).saturating_add(Self::latest_weight(&msg))
+	}
+
+	/// Applies a protocol message of any supported version from `sender`.
+	fn receive(sender: &MultiLocation, msg: VersionedXCMPMessageOf<T>) -> DispatchResult {
+		Self::note_version(sender, msg.version());
+		Self::note_seen(sender);
+		// version 1 partners only ever talk about the current order of a device
+		let msg = msg.into_latest(|_, device| {
+			Orders::<T>::get(device).map(|order| order.id).unwrap_or_default()
+		});
+		Self::handle_message(sender, msg)
+	}
+
+	fn on_undelivered(msg: XCMPMessageOf<T>) {
+		match msg {
+			XCMPMessageOf::<T>::NewOrder(id, _, order) => {
+				Self::refund_undelivered(id, &order.device)
+			},
+			// the service chain hasn't heard of the cancellation, the device is owed nothing
+			XCMPMessageOf::<T>::CancelOrder(id, client, device) => {
+				Self::on_cancelled(None, id, client, device, Zero::zero())
+			},
+			_ => {},
+		}
+	}
+
+	/// An order settled while waiting for a retry, by a cancellation or a deadline, is not
+	/// sent anymore.
+	fn is_stale(msg: &XCMPMessageOf<T>) -> bool {
+		match msg {
+			XCMPMessageOf::<T>::NewOrder(id, _, order) => {
+				let current = Orders::<T>::get(&order.device).map_or(false, |o| o.id == *id);
+				let busy = Device::<T>::get(&order.device)
+					.map_or(false, |dev| dev.state == DeviceState::Busy);
+				!(current && busy)
+			},
+			_ => false,
+		}
+	}
+
+	/// Sends `msg` to `dest`, down-converted to the version negotiated with it. The outcome of
+	/// a program carrying an order is queried, the order is refunded unless the service chain
+	/// reports it has executed it in time.
+	fn try_send(dest: &MultiLocation, msg: XCMPMessageOf<T>) -> Result<(), TransportError> {
+		let report = match msg {
+			XCMPMessageOf::<T>::NewOrder(id, _, ref order) => Some((id, order.device.clone())),
+			_ => None,
+		};
+		let msg = Self::versioned_for(dest, msg)?;
+		let (query, appendix) = match report {
+			Some(report) => {
+				// this chain as the service chain sees it
+				let here = T::LocationInverter::invert_location(dest)
+					.map_err(|_| TransportError::CannotReachDestination)?;
+				let timeout = frame_system::Pallet::<T>::block_number()
+					.saturating_add(T::DeliveryTimeout::get());
+				let notify = Call::<T>::transact_status { query_id: 0, response: Response::Null };
+				let max_response_weight = notify.get_dispatch_info().weight;
+				let query_id = T::NotifyQuery::new_notify_query(dest.clone(), notify, timeout);
+				let appendix = Xcm(vec![Instruction::ReportError {
+					query_id,
+					dest: here,
+					max_response_weight,
+				}]);
+				(Some((query_id, timeout, report)), Some(appendix))
+			},
+			None => (None, None),
+		};
+		Self::send_versioned(dest, msg, appendix)?;
+		if let Some((query_id, timeout, report)) = query {
+			OrderQuery::<T>::insert(report.0, query_id);
+			PendingDelivery::<T>::insert(query_id, report);
+			DeliveryTimeouts::<T>::append(timeout, query_id);
+		}
+		Ok(())
+	}
+}
+
+impl<T: Config> From<TransportError> for Error<T> {
+	fn from(e: TransportError) -> Self {
+		match e {
+			TransportError::CannotReachDestination => Error::<T>::CannotReachDestination,
+			TransportError::UnsupportedVersion => Error::<T>::UnsupportedVersion,
+			TransportError::BadXcmVersion => Error::<T>::BadXcmVersion,
+			TransportError::TransferFailed => Error::<T>::TransferFailed,
+			TransportError::UnknownOverweight => Error::<T>::UnknownOverweight,
+			TransportError::WeightOverLimit => Error::<T>::WeightOverLimit,
+		}
+	}
+}
+
+impl<T: Config> Pallet<T> {
+	fn latest_weight(msg: &XCMPMessageOf<T>) -> Weight {
+		let db = T::DbWeight::get();
+		match msg {
+			XCMPMessageOf::<T>::OrderAccept(..) => db.reads_writes(3, 2),
+			XCMPMessageOf::<T>::OrderReject(..) => db.reads_writes(6, 6),
+			XCMPMessageOf::<T>::OrderDone(..) => db.reads_writes(6, 8),
+			XCMPMessageOf::<T>::OrderProgress(..) => db.reads_writes(3, 2),
+			XCMPMessageOf::<T>::Version(_) => db.reads_writes(1, 2),
+			XCMPMessageOf::<T>::Batch(msgs) => {
+				msgs.iter().map(Self::latest_weight).fold(0, Weight::saturating_add)
+			},
+			XCMPMessageOf::<T>::Ping(_) => db.reads_writes(3, 1),
+			XCMPMessageOf::<T>::Pong(_) => db.reads_writes(1, 2),
+			XCMPMessageOf::<T>::DeviceRegistered(..) | XCMPMessageOf::<T>::DeviceUpdated(..) => {
+				db.reads_writes(4, 6)
+			},
+			XCMPMessageOf::<T>::DeviceRemoved(_) => db.reads_writes(4, 4),
+			XCMPMessageOf::<T>::OrderCancelled(..) => db.reads_writes(4, 4),
+			XCMPMessageOf::<T>::DeviceSchemas(..) => db.reads_writes(2, 1),
+			// not meant for a client chain, dropped
+			XCMPMessageOf::<T>::NewOrder(..)
+			| XCMPMessageOf::<T>::OrderExpired(..)
+			| XCMPMessageOf::<T>::CancelOrder(..) => 0,
+		}
+	}
+
+	/// Applies a protocol message from `sender`. The messages of a batch are applied one at
+	/// a time, a failing one is rolled back without affecting the others.
+	fn handle_message(sender: &MultiLocation, msg: XCMPMessageOf<T>) -> DispatchResult {
+		match msg {
+			XCMPMessageOf::<T>::OrderAccept(_, _, ref devid)
+			| XCMPMessageOf::<T>::OrderReject(_, _, ref devid, _)
+			| XCMPMessageOf::<T>::OrderDone(_, _, ref devid, _, _)
+			| XCMPMessageOf::<T>::OrderProgress(_, _, ref devid, _, _)
+				if !Self::authorize(sender, devid) =>
+			{
+				Ok(())
+			},
+			XCMPMessageOf::<T>::OrderAccept(id, client, devid) => {
+				log::info!("OrderAccept");
+				Self::on_accept(id, client, devid)
+			},
+			XCMPMessageOf::<T>::OrderReject(id, client, devid, onoff) => {
+				log::info!("OrderReject");
+				Self::on_reject(id, client, devid, onoff)
+			},
+			XCMPMessageOf::<T>::OrderDone(id, cliend, devid, onoff, result) => {
+				log::info!("OrderDone");
+				Self::on_done(id, cliend, devid, onoff, result)
+			},
+			XCMPMessageOf::<T>::OrderProgress(id, client, devid, percent, status) => {
+				Self::on_progress(id, client, devid, percent, status)
+			},
+			XCMPMessageOf::<T>::Version(version) => {
+				Self::set_partner_version(sender, version);
+				Ok(())
+			},
+			XCMPMessageOf::<T>::Ping(nonce) => {
+				// only chains we order from learn whether we are alive
+				if !TrustedChains::<T>::contains_key(sender) {
+					return Err(Error::<T>::UntrustedChain.into());
+				}
+				Self::try_send(sender, XCMPMessageOf::<T>::Pong(nonce))
+					.map_err(Error::<T>::from)?;
+				Ok(())
+			},
+			XCMPMessageOf::<T>::Pong(nonce) => {
+				Self::on_pong(sender, nonce);
+				Ok(())
+			},
+			XCMPMessageOf::<T>::DeviceRegistered(devid, penalty, wcd, onoff, cancel_fee)
+			| XCMPMessageOf::<T>::DeviceUpdated(devid, penalty, wcd, onoff, cancel_fee) => {
+				Self::on_device_announced(sender, devid, penalty, wcd, onoff, cancel_fee)
+			},
+			XCMPMessageOf::<T>::DeviceRemoved(devid) => Self::on_device_removed(sender, devid),
+			XCMPMessageOf::<T>::DeviceSchemas(devid, schemas) => {
+				Self::on_device_schemas(sender, devid, schemas)
+			},
+			XCMPMessageOf::<T>::OrderCancelled(id, client, devid, fee) => {
+				Self::on_cancelled(Some(sender), id, client, devid, fee);
+				Ok(())
+			},
+			XCMPMessageOf::<T>::Batch(msgs) => {
+				for msg in msgs {
+					if matches!(msg, XCMPMessageOf::<T>::Batch(_)) {
+						log::warn!("nested batch from {:?} ignored", sender);
+						continue;
+					}
+					let result = with_transaction(|| match Self::handle_message(sender, msg) {
+						Ok(()) => TransactionOutcome::Commit(Ok(())),
+						Err(e) => TransactionOutcome::Rollback(Err(e)),
+					});
+					if let Err(e) = result {
+						log::warn!("batched message from {:?} failed: {:?}", sender, e);
+					}
+				}
+				Ok(())
+			},
+			XCMPMessageOf::<T>::NewOrder(..)
+			| XCMPMessageOf::<T>::OrderExpired(..)
+			| XCMPMessageOf::<T>::CancelOrder(..) => {
+				log::warn!("unknown XCM message received");
+				Ok(())
+			},
 		}
-		max_weight
 	}
 }
@@ -11,7 +11,10 @@ use sp_api::impl_runtime_apis;
 use sp_core::{crypto::KeyTypeId, OpaqueMetadata};
 use sp_runtime::{
 	create_runtime_str, generic, impl_opaque_keys,
-	traits::{AccountIdLookup, BlakeTwo256, Block as BlockT, IdentifyAccount, Verify},
+	traits::{
+		AccountIdConversion, AccountIdLookup, BlakeTwo256, Block as BlockT, IdentifyAccount,
+		Verify,
+	},
 	transaction_validity::{TransactionSource, TransactionValidity},
 	ApplyExtrinsicResult, MultiSignature,
 };
@@ -23,7 +26,7 @@ use sp_version::RuntimeVersion;
 
 use frame_support::{
 	construct_runtime, match_type, parameter_types,
-	traits::{Everything, Nothing},
+	traits::{Currency, Everything, Nothing, OnUnbalanced},
 	weights::{
 		constants::{BlockExecutionWeight, ExtrinsicBaseWeight, WEIGHT_PER_SECOND},
 		DispatchClass, IdentityFee, Weight, WeightToFeeCoefficient, WeightToFeeCoefficients,
@@ -49,7 +52,8 @@ use polkadot_runtime_common::{BlockHashCount, RocksDbWeight, SlowAdjustingFeeUpd
 // XCM Imports
 use xcm::latest::prelude::*;
 use xcm_builder::{
-	AccountId32Aliases, AllowTopLevelPaidExecutionFrom, AllowUnpaidExecutionFrom, CurrencyAdapter,
+	AccountId32Aliases, AllowKnownQueryResponses, AllowSubscriptionsFrom,
+	AllowTopLevelPaidExecutionFrom, AllowUnpaidExecutionFrom, CurrencyAdapter,
 	EnsureXcmOrigin, FixedWeightBounds, IsConcrete, LocationInverter, NativeAsset, ParentIsDefault,
 	RelayChainAsNative, SiblingParachainAsNative, SiblingParachainConvertsVia,
 	SignedAccountId32AsNative, SignedToAccountId32, SovereignSignedViaLocation, TakeWeightCredit,
@@ -120,8 +124,43 @@ pub type Executive = frame_executive::Executive<
 	frame_system::ChainContext<Runtime>,
 	Runtime,
 	AllPalletsWithSystem,
+	(
+		SetSafeXcmVersion,
+		// orders were placed with `u64` payloads before the schema registry
+		pallet_xchange::migrations::v1::MigrateToV1<Runtime, u64>,
+		pallet_xchange::migrations::v2::MigrateToV2<Runtime>,
+		pallet_xchange::migrations::v3::MigrateToV3<Runtime>,
+		pallet_xchange::migrations::v4::MigrateToV4<Runtime>,
+		pallet_xchange::migrations::v5::MigrateToV5<Runtime>,
+		pallet_xchange_service::migrations::v1::MigrateToV1<Runtime, u64>,
+		pallet_xchange_service::migrations::v2::MigrateToV2<Runtime>,
+		pallet_xchange_service::migrations::v3::MigrateToV3<Runtime>,
+		pallet_xchange_service::migrations::v4::MigrateToV4<Runtime>,
+	),
 >;
 
+/// XCM version assumed for partner chains until they tell theirs.
+pub const SAFE_XCM_VERSION: u32 = xcm::latest::VERSION;
+
+/// Sets `SAFE_XCM_VERSION` on chains started before it was part of the genesis. Without it
+/// nothing can be sent to a partner whose version isn't known yet, not even the request for
+/// its version.
+pub struct SetSafeXcmVersion;
+impl frame_support::traits::OnRuntimeUpgrade for SetSafeXcmVersion {
+	fn on_runtime_upgrade() -> Weight {
+		use frame_support::storage::migration::get_storage_value;
+
+		if get_storage_value::<u32>(b"PolkadotXcm", b"SafeXcmVersion", &[]).is_none() {
+			if let Err(e) =
+				PolkadotXcm::force_default_xcm_version(Origin::root(), Some(SAFE_XCM_VERSION))
+			{
+				log::error!("safe XCM version can't be set: {:?}", e);
+			}
+		}
+		RocksDbWeight::get().reads_writes(1, 1)
+	}
+}
+
 /// Handles converting a weight scalar to a fee value, based on the scale and granularity of the
 /// node's balance type.
 ///
@@ -455,6 +494,10 @@ pub type Barrier = (
 	AllowTopLevelPaidExecutionFrom<Everything>,
 	AllowUnpaidExecutionFrom<ParentOrParentsExecutivePlurality>,
 	// ^^^ Parent and its exec plurality get free execution
+	// Partners ask for our XCM version and tell theirs
+	AllowSubscriptionsFrom<Everything>,
+	// reports of the messages carrying orders
+	AllowKnownQueryResponses<PolkadotXcm>,
 );
 
 pub struct XcmConfig;
@@ -522,7 +565,7 @@ impl cumulus_pallet_xcmp_queue::Config for Runtime {
 	type Event = Event;
 	type XcmExecutor = XcmExecutor<XcmConfig>;
 	type ChannelInfo = ParachainSystem;
-	type VersionWrapper = ();
+	type VersionWrapper = PolkadotXcm;
 }
 
 impl cumulus_pallet_dmp_queue::Config for Runtime {
@@ -590,11 +633,100 @@ impl pallet_template::Config for Runtime {
 	type Event = Event;
 }
 
+parameter_types! {
+	pub const MaxDeliveryAttempts: u32 = 5;
+	pub const DeliveryBackoff: BlockNumber = 2;
+	pub const MaxStatusLen: u32 = 256;
+	pub const MaxPayloadLen: u32 = 1024;
+	pub const MaxDeviceSchemas: u32 = 16;
+	pub const DeliveryTimeout: BlockNumber = 10;
+	pub const PingInterval: BlockNumber = 10;
+	pub const UnreachableAfter: BlockNumber = 50;
+	// Time a service chain has to report past the acceptance window of a device
+	pub const AcceptGrace: u64 = 10 * MILLISECS_PER_BLOCK;
+	// Devices letting the acceptance window pass lose their whole penalty
+	pub const AcceptTimeoutForfeit: Perbill = Perbill::from_percent(100);
+	pub const MaxQueuedOrders: u32 = 8;
+	pub const MaxBatchedMessages: u32 = 64;
+	// Pays the execution of our protocol messages on partner chains
+	pub const MessageFundId: PalletId = PalletId(*b"xchg/fnd");
+	pub MessageFundAccount: AccountId = MessageFundId::get().into_account();
+	// Deadlines are indexed by the block they fall in
+	pub const DeadlineSliceLen: u64 = MILLISECS_PER_BLOCK;
+	// Pallet indices of the order protocol on partner chains
+	pub const XchangePalletIndex: u8 = 92;
+	pub const XchangeServiceIndex: u8 = 93;
+}
+
+/// Price of weight on partner chains, they trade it one to one like our `Trader`.
+pub struct XcmFeeAmount;
+impl sp_runtime::traits::Convert<Weight, u128> for XcmFeeAmount {
+    fn convert(weight: Weight) -> u128 {
+        IdentityFee::<Balance>::calc(&weight)
+    }
+}
+
+/// Puts the message fees into the account refilling our sovereign accounts on partner chains.
+pub struct ToMessageFund;
+impl OnUnbalanced<pallet_balances::NegativeImbalance<Runtime>> for ToMessageFund {
+    fn on_nonzero_unbalanced(fee: pallet_balances::NegativeImbalance<Runtime>) {
+        Balances::resolve_creating(&MessageFundAccount::get(), fee);
+    }
+}
+
+/// Queries partner chains through `pallet_xcm`.
+pub struct XcmQueries;
+impl<Notify: Into<Call>> pallet_xchange::NotifyQuery<Notify, BlockNumber> for XcmQueries {
+    fn new_notify_query(
+        responder: MultiLocation,
+        notify: Notify,
+        timeout: BlockNumber,
+    ) -> QueryId {
+        PolkadotXcm::new_notify_query(responder, notify, timeout)
+    }
+}
+
+/// Location of an account, the inverse of `AccountId32Aliases` in `LocationToAccountId`.
+pub struct AccountIdToMultiLocation;
+impl sp_runtime::traits::Convert<AccountId, MultiLocation> for AccountIdToMultiLocation {
+    fn convert(account: AccountId) -> MultiLocation {
+        Junction::AccountId32 { network: RelayNetwork::get(), id: account.into() }.into()
+    }
+}
+
 impl pallet_xchange::Config for Runtime {
     type Event = Event;
     type XcmpMessageSender = XcmRouter;
-    type OrderPayload = u64;
+    type LocationToAccountId = LocationToAccountId;
+    type LocationInverter = LocationInverter<Ancestry>;
+    type VersionWrapper = PolkadotXcm;
+    type NotifyQuery = XcmQueries;
+    type ResponseOrigin = pallet_xcm::EnsureResponse<Everything>;
+    type DeliveryTimeout = DeliveryTimeout;
+    type ServicePalletIndex = XchangeServiceIndex;
+    type FeeAsset = RelayLocation;
+    type FeeAmount = XcmFeeAmount;
+    type XcmInstructionWeight = UnitWeightCost;
+    type OnXcmFee = ToMessageFund;
+    type MessageFund = MessageFundAccount;
+    type XcmExecutor = XcmExecutor<XcmConfig>;
+    type AccountIdToMultiLocation = AccountIdToMultiLocation;
+    // the relay token, settled through the relay chain
+    type SettlementAsset = RelayLocation;
+    type OrderPayload = pallet_xchange::SchemaPayload<MaxPayloadLen>;
+    type OrderResult = Hash;
     type Currency = Balances;
+    type SelfParaId = parachain_info::Pallet<Runtime>;
+    type MaxDeliveryAttempts = MaxDeliveryAttempts;
+    type DeliveryBackoff = DeliveryBackoff;
+    type MaxStatusLen = MaxStatusLen;
+    type PingInterval = PingInterval;
+    type UnreachableAfter = UnreachableAfter;
+    type ChannelInfo = ParachainSystem;
+    type AcceptGrace = AcceptGrace;
+    type MaxQueuedOrders = MaxQueuedOrders;
+    type MaxDeviceSchemas = MaxDeviceSchemas;
+    type DeadlineSliceLen = DeadlineSliceLen;
 }
 
 impl cumulus_ping::Config for Runtime {
@@ -618,10 +750,32 @@ impl<T: pallet_xchange_service::Config> pallet_xchange_service::OnReceived<T> fo
 impl pallet_xchange_service::Config for Runtime {
     type Event = Event;
     type XcmpMessageSender = XcmRouter;
-    type OrderPayload = u64;
+    type LocationToAccountId = LocationToAccountId;
+    type LocationInverter = LocationInverter<Ancestry>;
+    type VersionWrapper = PolkadotXcm;
+    type ClientPalletIndex = XchangePalletIndex;
+    type FeeAsset = RelayLocation;
+    type FeeAmount = XcmFeeAmount;
+    type XcmInstructionWeight = UnitWeightCost;
+    type OnXcmFee = ToMessageFund;
+    type MessageFund = MessageFundAccount;
+    type XcmExecutor = XcmExecutor<XcmConfig>;
+    type AccountIdToMultiLocation = AccountIdToMultiLocation;
+    // the relay token, settled through the relay chain
+    type SettlementAsset = RelayLocation;
+    type OrderPayload = pallet_xchange_service::SchemaPayload<MaxPayloadLen>;
+    type OrderResult = Hash;
     type Currency = Balances;
     type SelfParaId = parachain_info::Pallet<Runtime>;
     type OnReceived = AcceptOnReceive;
+    type MaxDeliveryAttempts = MaxDeliveryAttempts;
+    type DeliveryBackoff = DeliveryBackoff;
+    type MaxStatusLen = MaxStatusLen;
+    type MaxDeviceSchemas = MaxDeviceSchemas;
+    type AcceptTimeoutForfeit = AcceptTimeoutForfeit;
+    type MaxQueuedOrders = MaxQueuedOrders;
+    type DeadlineSliceLen = DeadlineSliceLen;
+    type MaxBatchedMessages = MaxBatchedMessages;
 }
 // Create the runtime by composing the FRAME pallets that were previously configured.
 construct_runtime!(
@@ -651,7 +805,7 @@ construct_runtime!(
 
 		// XCM helpers.
 		XcmpQueue: cumulus_pallet_xcmp_queue::{Pallet, Call, Storage, Event<T>} = 30,
-		PolkadotXcm: pallet_xcm::{Pallet, Call, Event<T>, Origin} = 31,
+		PolkadotXcm: pallet_xcm::{Pallet, Call, Event<T>, Origin, Config} = 31,
 		CumulusXcm: cumulus_pallet_xcm::{Pallet, Event<T>, Origin} = 32,
 		DmpQueue: cumulus_pallet_dmp_queue::{Pallet, Call, Storage, Event<T>} = 33,
 